@@ -1,17 +1,73 @@
-use phoenix_rec::client::create_client;
+use phoenix_rec::client::{Client, ClientEvent};
+use phoenix_rec::config::ClientConfig;
 use phoenix_rec::debug;
+use phoenix_rec::protocol::Request;
+use std::io::BufRead;
+
+/// Parses a line typed by the user into a request for the server.
+fn parse_request(line: &str) -> Option<Request> {
+    let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+    match command {
+        "start" => Some(Request::StartRecording),
+        "stop" => Some(Request::StopRecording),
+        "clear" => Some(Request::ClearData),
+        "delete" => arg.parse().ok().map(Request::DeleteData),
+        "write" if !arg.is_empty() => Some(Request::WriteData(arg.to_string())),
+        "comment" if !arg.is_empty() => Some(Request::AddComment(arg.to_string())),
+        "len" => Some(Request::GetRecLen),
+        _ => None,
+    }
+}
 
 fn main() {
     debug!("Debugging client.rs");
-    let args = std::env::args().collect::<Vec<String>>();
-    // if args[1] is not none we take that as the server_name else localhost
-    let default = "localhost".to_string();
-    let (_main_sender, thread_receiver) = std::sync::mpsc::channel();
-    let (thread_sender, _main_receiver) = std::sync::mpsc::channel();
-    create_client(
-        args.get(1).unwrap_or(&default).to_string(),
-        thread_receiver,
-        thread_sender,
-    );
-    loop {}
+    // usage: client [server_name] [--port <port>] [--connect-timeout <ms>] [--read-timeout <ms>]
+    //               [--heartbeat <ms>] [--reconnect-delay <ms>] [--max-reconnect-delay <ms>]
+    // the server_name defaults to localhost
+    let config = match ClientConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    println!("Connecting to server: {}", config.address());
+    let client = match Client::connect(config, None) {
+        Ok(client) => client,
+        Err(e) => {
+            println!("Failed to connect: {}", e);
+            return;
+        }
+    };
+    println!("Connected, commands: start, stop, clear, delete <n>, write <file>, comment <text>, len, exit");
+    let handle = client.handle();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            let line = line.trim();
+            if line == "exit" {
+                break;
+            }
+            match parse_request(line) {
+                Some(request) => {
+                    if let Err(e) = handle.request(request) {
+                        println!("Failed to send request: {}", e);
+                    }
+                }
+                None => println!("Unknown command: {}", line),
+            }
+        }
+        if let Err(e) = handle.close() {
+            println!("Failed to close connection: {}", e);
+        }
+    });
+    for event in client {
+        match event {
+            ClientEvent::Data(data) => debug!("Data: {:?}", data),
+            event => println!("{:?}", event),
+        }
+    }
+    println!("Terminated.");
 }
@@ -1,11 +1,24 @@
+use phoenix_rec::config::ServerConfig;
 use phoenix_rec::data_types::DataType;
 use phoenix_rec::save_data;
-use phoenix_rec::server::{create_server, stop_server};
+use phoenix_rec::server::{create_server_with, stop_server};
 
 fn main() {
-    create_server();
+    // usage: server [--bind <address>] [--port <port>] [--accept <ms>] [--queue <capacity>]
+    //               [--drop-policy <oldest|newest|downsample|block>] [--heartbeat <ms>]
+    //               [--peer-timeout <ms>] [--write-dir <directory>]
+    let config = match ServerConfig::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    create_server_with(config);
     save_data(vec![DataType::Distance(0)]);
     save_data(vec![DataType::CalcSpeed(0, 0)]);
     stop_server();
-    loop {}
+    loop {
+        std::thread::park();
+    }
 }
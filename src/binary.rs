@@ -0,0 +1,342 @@
+//! A compact binary file format for recordings, built from the same pieces as the wire protocol.
+//!
+//! | bytes | content                                                        |
+//! |-------|----------------------------------------------------------------|
+//! | 4     | [`BINARY_MAGIC`]                                               |
+//! | 2     | [`BINARY_VERSION`] as u16 little endian                        |
+//! | 4     | length of the header, u32 little endian, at most `MAX_FRAME_LEN` |
+//! | n     | bincode of a [`BinaryHeader`]                                  |
+//! | ...   | chunks of records, every chunk is a `Data` frame of the protocol |
+//!
+//! Every chunk can be decoded on its own, so a file can be written while recording and a file
+//! that was cut off (e.g. because the battery died) can be read up to its last complete chunk.
+use crate::data_types::{
+    custom_channels, register_custom_channels, CustomChannel, DataType, DATA_TYPE_ID_VERSION,
+};
+use crate::protocol::{read_message_body, write_message, Message, MAX_FRAME_LEN};
+use crate::writer::write_csv;
+use crate::{Data, Error, RecData, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use whoami::fallible;
+
+/// First bytes of every binary recording.
+pub const BINARY_MAGIC: &[u8; 4] = b"PHXB";
+/// Version of the binary format, bump this whenever the layout or the header changes.
+pub const BINARY_VERSION: u16 = 1;
+/// Records per chunk used by `write_binary`.
+pub const DEFAULT_CHUNK_SIZE: usize = 256;
+
+/// Describes the recording, it is written once at the start of the file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BinaryHeader {
+    /// `DATA_TYPE_ID_VERSION` of the writer, the ids of the data types depend on it
+    pub data_type_id_version: u8,
+    /// Column and description of every data type known to the writer, including custom channels
+    pub columns: Vec<(u8, String)>,
+    /// Custom channels of the writer, they are registered when the file is read
+    pub custom_channels: Vec<CustomChannel>,
+    pub data_name: String,
+    /// Date and time of the creation as "dd-mm-yyyy HH:MM:SS"
+    pub created: String,
+    pub user: String,
+    pub machine: String,
+    /// Key/value pairs describing the recording, see `RecData::header_metadata`
+    pub metadata: Vec<(String, String)>,
+}
+
+impl BinaryHeader {
+    pub fn new(data_name: &str, metadata: &[(String, String)]) -> Self {
+        Self {
+            data_type_id_version: DATA_TYPE_ID_VERSION,
+            columns: DataType::columns()
+                .into_iter()
+                .filter_map(|c| DataType::column_description(c).map(|d| (c, d)))
+                .collect(),
+            custom_channels: custom_channels(),
+            data_name: data_name.to_string(),
+            created: chrono::Local::now().format("%d-%m-%Y %H:%M:%S").to_string(),
+            user: whoami::username(),
+            machine: fallible::hostname().unwrap_or_else(|_| "unknown".to_string()),
+            metadata: metadata.to_vec(),
+        }
+    }
+
+    /// Registers the custom channels of the file and fails if the data types of the file don't
+    /// match the data types of this program.
+    fn check(&self) -> Result<()> {
+        if self.data_type_id_version != DATA_TYPE_ID_VERSION {
+            return Err(Error::Parse(format!(
+                "File uses data type ids version {}, expected {}",
+                self.data_type_id_version, DATA_TYPE_ID_VERSION
+            )));
+        }
+        register_custom_channels(&self.custom_channels)?;
+        for (column, description) in &self.columns {
+            match DataType::column_description(*column) {
+                Some(d) if d != *description => {
+                    return Err(Error::Parse(format!(
+                        "Column {} is \"{}\" in the file, but \"{}\" here",
+                        column, description, d
+                    )));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a binary recording, the records are written in chunks of `chunk_size`.
+#[derive(Debug)]
+pub struct BinaryWriter {
+    file: BufWriter<File>,
+    chunk: Vec<Data>,
+    chunk_size: usize,
+}
+
+impl BinaryWriter {
+    /// Creates the file and writes its header with the metadata.
+    pub fn create(
+        file_name: &str,
+        data_name: &str,
+        metadata: &[(String, String)],
+        chunk_size: usize,
+    ) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_name)?;
+        let mut file = BufWriter::new(file);
+        let header = bincode::serialize(&BinaryHeader::new(data_name, metadata))?;
+        file.write_all(BINARY_MAGIC)?;
+        file.write_all(&BINARY_VERSION.to_le_bytes())?;
+        file.write_all(&(header.len() as u32).to_le_bytes())?;
+        file.write_all(&header)?;
+        file.flush()?;
+        Ok(Self {
+            file,
+            chunk: Vec::with_capacity(chunk_size),
+            chunk_size: chunk_size.max(1),
+        })
+    }
+
+    /// Adds the data to the current chunk, which is written once it is full.
+    pub fn write(&mut self, data: Data) -> Result<()> {
+        self.chunk.push(data);
+        if self.chunk.len() >= self.chunk_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the current chunk to the file, even if it is not full.
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.chunk.is_empty() {
+            write_message(&mut self.file, &Message::Data(std::mem::take(&mut self.chunk)))?;
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for BinaryWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            println!("Failed to write the last chunk: {}", e);
+        }
+    }
+}
+
+/// Reads a binary recording chunk by chunk.
+///
+/// The iterator ends at the end of the file. A chunk that was cut off is returned as an
+/// `Error::Io` with `ErrorKind::UnexpectedEof`, all chunks before it are intact.
+#[derive(Debug)]
+pub struct BinaryReader {
+    file: BufReader<File>,
+    header: BinaryHeader,
+    done: bool,
+}
+
+impl BinaryReader {
+    /// Opens the file and reads its header.
+    pub fn open(file_name: &str) -> Result<Self> {
+        let mut file = BufReader::new(File::open(file_name)?);
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != BINARY_MAGIC {
+            return Err(Error::Parse(format!("{} is not a binary recording", file_name)));
+        }
+        let mut version = [0u8; 2];
+        file.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != BINARY_VERSION {
+            return Err(Error::Parse(format!(
+                "Unsupported binary format version {}, expected {}",
+                version, BINARY_VERSION
+            )));
+        }
+        let mut len = [0u8; 4];
+        file.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(Error::Parse(format!(
+                "Header of {} bytes is larger than the maximum of {} bytes",
+                len, MAX_FRAME_LEN
+            )));
+        }
+        let mut header = vec![0u8; len];
+        file.read_exact(&mut header)?;
+        let header: BinaryHeader = bincode::deserialize(&header)?;
+        header.check()?;
+        Ok(Self {
+            file,
+            header,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &BinaryHeader {
+        &self.header
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Vec<Data>>> {
+        let mut ty = [0u8; 1];
+        match self.file.read_exact(&mut ty) {
+            Ok(()) => {}
+            // the file ends between two chunks
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        match read_message_body(&mut self.file, ty[0])? {
+            Message::Data(data) => Ok(Some(data)),
+            m => Err(Error::Parse(format!(
+                "Expected a chunk of data, got {:?}",
+                m.message_type()
+            ))),
+        }
+    }
+}
+
+impl Iterator for BinaryReader {
+    type Item = Result<Vec<Data>>;
+
+    fn next(&mut self) -> Option<Result<Vec<Data>>> {
+        if self.done {
+            return None;
+        }
+        let chunk = self.read_chunk().transpose();
+        // don't try to read after the end or an error, the position in the file is unknown
+        if !matches!(chunk, Some(Ok(_))) {
+            self.done = true;
+        }
+        chunk
+    }
+}
+
+/// Reads a binary recording into a `RecData` and its header.
+/// If the file was cut off, everything up to the last complete chunk is returned.
+pub fn read_binary_file(file_name: &str) -> Result<(BinaryHeader, RecData)> {
+    let mut reader = BinaryReader::open(file_name)?;
+    let header = reader.header().clone();
+    let mut data = vec![];
+    for chunk in &mut reader {
+        match chunk {
+            Ok(chunk) => data.extend(chunk),
+            Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                println!(
+                    "{} was cut off, recovered {} entries",
+                    file_name,
+                    data.len()
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let rec_data = RecData::from_file(data, header.metadata.clone())?;
+    Ok((header, rec_data))
+}
+
+/// Converts a binary recording to the CSV layout of `write_data`.
+pub fn binary_to_csv(binary_file: &str, csv_file: &str) -> Result<()> {
+    let (header, rec_data) = read_binary_file(binary_file)?;
+    write_csv(
+        csv_file,
+        &rec_data.data,
+        &header.data_name,
+        &rec_data.header_metadata(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::read_data;
+    use crate::reader::tests::{sample, temp_file, without_channels};
+
+    fn write_sample(file: &str, chunk_size: usize) -> (Vec<Data>, Vec<(String, String)>) {
+        let (data, metadata) = sample();
+        let mut writer = BinaryWriter::create(file, "data_test", &metadata, chunk_size).unwrap();
+        for d in &data {
+            writer.write(d.clone()).unwrap();
+        }
+        (data, metadata)
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let file = temp_file("round_trip.bin");
+        let (data, metadata) = write_sample(&file, 3);
+        let (header, rec_data) = read_binary_file(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(header.data_name, "data_test");
+        assert_eq!(header.metadata, metadata);
+        assert_eq!(rec_data.data, data);
+        assert_eq!(without_channels(rec_data.header_metadata()), metadata);
+    }
+
+    #[test]
+    fn truncated_file_keeps_complete_chunks() {
+        let file = temp_file("truncated.bin");
+        let (data, _) = write_sample(&file, 3);
+        let len = std::fs::metadata(&file).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        let (_, rec_data) = read_binary_file(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        // the last chunk holds the last 3 entries and is lost
+        assert_eq!(rec_data.data, data[..6]);
+    }
+
+    #[test]
+    fn converted_csv_matches_binary() {
+        let binary = temp_file("convert.bin");
+        let csv = temp_file("convert.csv");
+        let (data, metadata) = write_sample(&binary, DEFAULT_CHUNK_SIZE);
+        binary_to_csv(&binary, &csv).unwrap();
+        let rec_data = read_data(csv.clone()).unwrap();
+        std::fs::remove_file(&binary).unwrap();
+        std::fs::remove_file(&csv).unwrap();
+        assert_eq!(rec_data.data, data);
+        assert_eq!(without_channels(rec_data.header_metadata()), metadata);
+    }
+
+    #[test]
+    fn huge_header_is_rejected() {
+        let file = temp_file("huge_header.bin");
+        let mut content = BINARY_MAGIC.to_vec();
+        content.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+        content.extend_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&file, content).unwrap();
+        let result = BinaryReader::open(&file);
+        std::fs::remove_file(&file).unwrap();
+        assert!(matches!(result, Err(Error::Parse(_))));
+    }
+}
@@ -1,12 +1,21 @@
-use crate::{save_record_data, Data};
-use lz4_compression::prelude::decompress;
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::str::from_utf8;
+use crate::config::{ClientConfig, DEFAULT_PORT};
+use crate::data_types::DATA_TYPE_ID_VERSION;
+use crate::protocol::{
+    read_frame, read_message, write_message, Message, Reply, Request, PROTOCOL_VERSION,
+};
+use crate::{
+    debug, global_recorder, register_header_channels, save_record_data, Annotation, Data, Error,
+    Result, RECORDING_ID_KEY,
+};
+use std::collections::VecDeque;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
 use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
 
-pub const PORT: u16 = 3333;
+pub const PORT: u16 = DEFAULT_PORT;
 static CLIENT: AtomicBool = AtomicBool::new(false);
 
 pub fn client_alive() -> bool {
@@ -17,73 +26,667 @@ pub fn create_client(
     server_name: String,
     thread_receiver: Receiver<String>,
     thread_sender: Sender<String>,
+) {
+    create_client_replay(server_name, None, thread_receiver, thread_sender)
+}
+
+/// Same as `create_client`, but asks the server to first send the data it already recorded
+/// from the timestamp `replay_from` onward (0 for the whole history) before the live data.
+pub fn create_client_replay(
+    server_name: String,
+    replay_from: Option<u128>,
+    thread_receiver: Receiver<String>,
+    thread_sender: Sender<String>,
+) {
+    create_client_with(
+        ClientConfig::new(server_name),
+        replay_from,
+        thread_receiver,
+        thread_sender,
+    )
+}
+
+/// Same as `create_client_replay`, but connects to the address and port of the config.
+pub fn create_client_with(
+    config: ClientConfig,
+    replay_from: Option<u128>,
+    thread_receiver: Receiver<String>,
+    thread_sender: Sender<String>,
 ) {
     if CLIENT.load(std::sync::atomic::Ordering::SeqCst) {
         return;
     }
     CLIENT.store(true, std::sync::atomic::Ordering::SeqCst);
-    let server_name = format!("{}:{}", server_name, PORT);
+    let server_name = config.address();
     println!("Connecting to server: {}", server_name);
-    match TcpStream::connect(server_name.clone()) {
-        Ok(mut stream) => {
-            println!("Successfully connected to server in port 3333");
+    match Client::connect(config, replay_from) {
+        Ok(mut client) => {
+            println!("Successfully connected to server {}", server_name);
             thread_sender
                 .send(format!("Successfully connected to server {}", server_name))
                 .expect("Couldn't send to main thread");
+            receive_data(&mut client, &thread_receiver);
+        }
+        Err(e) => {
+            println!("Failed to connect: {}", e);
+        }
+    }
+    println!("Terminated.");
+    thread_sender
+        .send("Terminated".to_string())
+        .expect("Couldn't send to main thread");
+    CLIENT.store(false, std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Saves the received data into the global recorder until the server closes the connection
+/// or the main thread sends "exit".
+fn receive_data(client: &mut Client, thread_receiver: &Receiver<String>) {
+    while let Some(event) = client.next() {
+        match event {
+            ClientEvent::Data(d) => save_record_data(d),
+            ClientEvent::Annotation(t, a) => save_record_data(Data::Annotation(t, a)),
+            ClientEvent::Dropped(n) => println!("Server dropped {} records", n),
+            ClientEvent::ServerError(e) => println!("Server error: {}", e),
+            ClientEvent::Reply(reply) => println!("Reply: {:?}", reply),
+            ClientEvent::Metadata(metadata) => {
+                if let Err(e) = global_recorder().lock().set_header_metadata(metadata) {
+                    println!("Invalid metadata from server: {}", e);
+                }
+            }
+            ClientEvent::Reconnecting {
+                attempt,
+                delay,
+                error,
+            } => println!(
+                "Connection lost: {}, reconnecting in {:?} (attempt {})",
+                error, delay, attempt
+            ),
+            ClientEvent::Reconnected => println!("Reconnected"),
+            ClientEvent::Closed => {}
+            ClientEvent::Disconnected(e) => println!("Failed to receive data: {}", e),
+        }
+        // check if something has been sent over the main thread
+        if let Ok(msg) = thread_receiver.try_recv() {
+            if msg == "exit" {
+                if let Err(e) = client.close() {
+                    println!("Failed to close connection: {}", e);
+                }
+                break;
+            }
+        }
+    }
+}
 
-            let msg = b"hello";
+/// Everything a `Client` can receive from the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    Data(Data),
+    /// An annotation and the time it was added at, see `add_annotation`
+    Annotation(u128, Annotation),
+    /// The server dropped this many records because we did not keep up, the stream has a gap
+    Dropped(u64),
+    /// The server reported an error or sent a message that could not be decoded, like data of
+    /// custom channels that don't match ours. The connection stays open
+    ServerError(String),
+    /// The answer to a `Request` sent with `Client::request`, they arrive in the order of the
+    /// requests. A failed request is answered with `ServerError` instead.
+    Reply(Reply),
+    /// The key/value pairs describing the recording including its time unit, start time and
+    /// custom channels, sent after connecting and whenever they change, see
+    /// `RecData::header_metadata`. The custom channels are already registered
+    /// when this arrives
+    Metadata(Vec<(String, String)>),
+    /// The connection was lost, the client waits `delay` and then makes its `attempt`th try
+    /// to reconnect
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+        error: String,
+    },
+    /// The client is connected again and resumes after the last data it received
+    Reconnected,
+    /// The server or a `ClientHandle` closed the connection, this is the last event
+    Closed,
+    /// The connection was lost and reconnecting is disabled, this is the last event
+    Disconnected(String),
+}
 
-            stream.write(msg).unwrap();
-            println!("Sent Hello, awaiting reply...");
+/// Where to continue after a reconnect.
+///
+/// The server replays its history starting with the first record at `last_time` and the commands
+/// right before it, so we count what we already received of that tail and skip it.
+/// This only works within one recording, its id comes with the `Metadata`.
+#[derive(Debug, Default)]
+struct Resume {
+    /// id of the recording the received data belongs to
+    recording_id: Option<u64>,
+    last_time: Option<u128>,
+    /// Entries received since the last record before `last_time`
+    tail: usize,
+    /// Commands received since the last record
+    commands: usize,
+    /// Replayed entries that still have to be skipped
+    skip: usize,
+}
 
-            let mut data = [0u8; 5];
-            match stream.read_exact(&mut data) {
-                Ok(_) => {
-                    if &data == msg {
-                        println!("Reply is ok!");
-                    } else {
-                        let text = from_utf8(&data).unwrap();
-                        println!("Unexpected reply: {}", text);
-                    }
-                }
-                Err(e) => {
-                    println!("Failed to receive data: {}", e);
+impl Resume {
+    /// Takes the recording id of the `Metadata` from the server. If the recording changed, the
+    /// received timestamps mean nothing anymore and nothing is skipped, returns true then.
+    fn recording(&mut self, id: u64) -> bool {
+        let changed = self.recording_id.is_some_and(|current| current != id);
+        if changed {
+            *self = Resume::default();
+        }
+        self.recording_id = Some(id);
+        changed
+    }
+
+    fn received(&mut self, data: &Data) {
+        match data {
+            Data::Command(..) | Data::CommandEnd(..) | Data::Annotation(..) => {
+                self.tail += 1;
+                self.commands += 1;
+            }
+            Data::RecordData(t, _) | Data::RecordDataOption(t, _) => {
+                if self.last_time == Some(*t) {
+                    self.tail += 1;
+                } else {
+                    self.last_time = Some(*t);
+                    self.tail = self.commands + 1;
                 }
+                self.commands = 0;
             }
-            let mut data = [0u8; 4];
-            // stream.write(b"close").unwrap();
-            while let Ok(_) = stream.read(&mut data) {
-                // first 4 bytes are the length of the data
-                let len = u32::from_le_bytes(data);
-                if len == 0 {
-                    break;
+        }
+    }
+
+    /// Returns true if the replayed entry was already received before the reconnect.
+    fn already_received(&mut self, data: &Data) -> bool {
+        if self.skip == 0 {
+            return false;
+        }
+        match data {
+            Data::RecordData(t, _) | Data::RecordDataOption(t, _)
+                if Some(*t) > self.last_time =>
+            {
+                // the history has changed on the server, don't skip anything new
+                self.skip = 0;
+                false
+            }
+            _ => {
+                self.skip -= 1;
+                true
+            }
+        }
+    }
+}
+
+/// State shared between a `Client` and its `ClientHandle`s.
+#[derive(Debug, Default)]
+struct Shared {
+    closed: AtomicBool,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+/// A connection to the server on the robot.
+///
+/// The received data is not stored anywhere, the client is an iterator over the `ClientEvent`s
+/// instead. If the connection is lost, the client reconnects as configured and resumes after the
+/// last data it received. It ends after `Closed` or `Disconnected`.
+#[derive(Debug)]
+pub struct Client {
+    config: ClientConfig,
+    stream: TcpStream,
+    pending: VecDeque<ClientEvent>,
+    resume: Resume,
+    /// Where the first subscription started, used if no record was received before a reconnect.
+    /// 0 after the recording changed, since we follow the new one from its start
+    replay_from: Option<u128>,
+    /// Attempts to reconnect since the connection was lost, 0 while connected
+    attempt: u32,
+    /// Delay before the next reconnect attempt while the connection is lost
+    reconnect_in: Option<Duration>,
+    shared: Arc<Shared>,
+    done: bool,
+}
+
+impl Client {
+    /// Connects to the server, does the handshake and subscribes to the data.
+    /// If `replay_from` is given, the server first sends the data it already recorded from this
+    /// timestamp onward (0 for the whole history).
+    pub fn connect(config: ClientConfig, replay_from: Option<u128>) -> Result<Client> {
+        let stream = subscribe(&config, replay_from, None)?;
+        let shared = Arc::new(Shared::default());
+        *shared.stream.lock().unwrap() = Some(stream.try_clone()?);
+        let heartbeat = Arc::downgrade(&shared);
+        let interval = config.heartbeat_interval;
+        thread::spawn(move || send_heartbeats(heartbeat, interval));
+        Ok(Client {
+            config,
+            stream,
+            pending: VecDeque::new(),
+            resume: Resume::default(),
+            replay_from,
+            attempt: 0,
+            reconnect_in: None,
+            shared,
+            done: false,
+        })
+    }
+
+    /// Returns a handle that can close the connection from another thread.
+    pub fn handle(&self) -> ClientHandle {
+        ClientHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Tells the server that we are done, after this the iterator ends.
+    pub fn close(&mut self) -> Result<()> {
+        self.done = true;
+        self.shared
+            .closed
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        // written through the shared stream, so it can't interleave with a heartbeat
+        match self.shared.stream.lock().unwrap().as_mut() {
+            Some(stream) => write_message(stream, &Message::Close),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends a control request to the server, the answer arrives as `ClientEvent::Reply`.
+    pub fn request(&self, request: Request) -> Result<()> {
+        send_request(&self.shared, request)
+    }
+
+    /// Iterates only over the received data and skips all other events.
+    pub fn data(self) -> impl Iterator<Item = Data> {
+        self.filter_map(|e| match e {
+            ClientEvent::Data(d) => Some(d),
+            _ => None,
+        })
+    }
+
+    fn closed(&self) -> bool {
+        self.shared.closed.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    fn receive(&mut self) -> ClientEvent {
+        loop {
+            let (ty, payload) = match read_frame(&mut self.stream) {
+                Ok(frame) => frame,
+                Err(e) => return self.lost(e.or_timeout(self.config.read_timeout)),
+            };
+            let message = match Message::from_payload(ty, payload) {
+                Ok(message) => message,
+                // the frame was read completely, so the connection is still usable. Reconnecting
+                // would only replay the same frame
+                Err(e) => return ClientEvent::ServerError(format!("Invalid {:?}: {}", ty, e)),
+            };
+            match message {
+                Message::Data(data) => {
+                    if let Some(event) = self.queue_new(data) {
+                        return event;
+                    }
                 }
-                let mut data = vec![0u8; len as usize];
-                stream.read_exact(&mut data).unwrap();
-                // debug!("Received data: {:?}", data);
-                // convert back to data type
-                let data = decompress(&data).unwrap();
-                let data: Vec<Data> = bincode::deserialize(&data).unwrap();
-                // debug!("Received data: {:?}", data);
-                for d in data {
-                    save_record_data(d);
+                Message::Annotation(time, annotation) => {
+                    if let Some(event) = self.queue_new(vec![Data::Annotation(time, annotation)]) {
+                        return event;
+                    }
                 }
-                // check if something has been sent over the main thread
-                if let Ok(msg) = thread_receiver.try_recv() {
-                    if msg == "exit" {
-                        stream.write(b"close").unwrap();
-                        break;
+                Message::Dropped(n) => return ClientEvent::Dropped(n),
+                Message::Error(e) => return ClientEvent::ServerError(e),
+                Message::Reply(reply) => return ClientEvent::Reply(reply),
+                Message::Metadata(metadata) => {
+                    if let Err(e) = register_header_channels(&metadata) {
+                        self.pending
+                            .push_back(ClientEvent::ServerError(e.to_string()));
                     }
+                    let id = metadata
+                        .iter()
+                        .find(|(key, _)| key == RECORDING_ID_KEY)
+                        .and_then(|(_, value)| value.parse().ok())
+                        .unwrap_or(0);
+                    if self.resume.recording(id) {
+                        // we follow the new recording from its start
+                        self.replay_from = Some(0);
+                    }
+                    self.pending.push_back(ClientEvent::Metadata(metadata));
+                    return self.pending.pop_front().expect("metadata was queued");
                 }
+                Message::Close => return ClientEvent::Closed,
+                Message::Heartbeat => {}
+                m => debug!("Ignoring message: {:?}", m),
             }
         }
-        Err(e) => {
-            println!("Failed to connect: {}", e);
+    }
+
+    /// Queues the entries that were not received before a reconnect and returns the first
+    /// pending event.
+    fn queue_new(&mut self, data: Vec<Data>) -> Option<ClientEvent> {
+        for d in data {
+            if !self.resume.already_received(&d) {
+                self.resume.received(&d);
+                self.pending.push_back(match d {
+                    Data::Annotation(time, annotation) => ClientEvent::Annotation(time, annotation),
+                    d => ClientEvent::Data(d),
+                });
+            }
         }
+        self.pending.pop_front()
+    }
+
+    /// Decides what happens after the connection was lost with the error `e`.
+    fn lost(&mut self, e: Error) -> ClientEvent {
+        match self.config.reconnect_delay {
+            // the connection was shut down by a `ClientHandle`
+            _ if self.closed() => ClientEvent::Closed,
+            Some(delay) => {
+                self.attempt += 1;
+                let delay = delay
+                    .saturating_mul(1 << (self.attempt - 1).min(16))
+                    .min(self.config.max_reconnect_delay);
+                self.reconnect_in = Some(delay);
+                ClientEvent::Reconnecting {
+                    attempt: self.attempt,
+                    delay,
+                    error: e.to_string(),
+                }
+            }
+            _ => ClientEvent::Disconnected(e.to_string()),
+        }
+    }
+
+    /// Waits `delay`, then connects again and resumes at the last received timestamp.
+    fn reconnect(&mut self, delay: Duration) -> ClientEvent {
+        std::thread::sleep(delay);
+        if self.closed() {
+            return ClientEvent::Closed;
+        }
+        let replay_from = self.resume.last_time.or(self.replay_from);
+        let recording_id = self.resume.recording_id;
+        match subscribe(&self.config, replay_from, recording_id)
+            .and_then(|s| Ok((s.try_clone()?, s)))
+        {
+            Ok((clone, stream)) => {
+                self.stream = stream;
+                let mut shared_stream = self.shared.stream.lock().unwrap();
+                if self.closed() {
+                    // closed by a handle while we were connecting
+                    let _ = write_message(&mut self.stream, &Message::Close);
+                    return ClientEvent::Closed;
+                }
+                *shared_stream = Some(clone);
+                self.attempt = 0;
+                self.resume.skip = match replay_from {
+                    Some(_) => self.resume.tail,
+                    None => 0,
+                };
+                ClientEvent::Reconnected
+            }
+            Err(e) => self.lost(e),
+        }
+    }
+}
+
+impl Iterator for Client {
+    type Item = ClientEvent;
+
+    fn next(&mut self) -> Option<ClientEvent> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        if self.done {
+            return None;
+        }
+        let event = match self.reconnect_in.take() {
+            Some(delay) => self.reconnect(delay),
+            None => self.receive(),
+        };
+        if matches!(event, ClientEvent::Closed | ClientEvent::Disconnected(_)) {
+            self.done = true;
+        }
+        Some(event)
+    }
+}
+impl Drop for Client {
+    fn drop(&mut self) {
+        // stops the heartbeats, even if a `ClientHandle` is still around
+        self.shared
+            .closed
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+/// Sends a `Heartbeat` every `interval` over the current connection of the client,
+/// until the client is closed or dropped.
+fn send_heartbeats(shared: Weak<Shared>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if shared.closed.load(std::sync::atomic::Ordering::SeqCst) {
+            return;
+        }
+        let mut stream = shared.stream.lock().unwrap();
+        if let Some(stream) = stream.as_mut() {
+            // a failed heartbeat is noticed by the reader of the client, which reconnects
+            if let Err(e) = write_message(stream, &Message::Heartbeat) {
+                debug!("Failed to send heartbeat: {}", e);
+            }
+        }
+    }
+}
+
+/// Closes the connection of a `Client` from another thread.
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    shared: Arc<Shared>,
+}
+
+impl ClientHandle {
+    /// Sends a control request to the server, the answer arrives as `ClientEvent::Reply`
+    /// at the client.
+    pub fn request(&self, request: Request) -> Result<()> {
+        send_request(&self.shared, request)
+    }
+
+    /// Tells the server that we are done, the client stops reconnecting and its iterator ends.
+    pub fn close(&self) -> Result<()> {
+        self.shared
+            .closed
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        if let Some(mut stream) = self.shared.stream.lock().unwrap().take() {
+            // the connection may already be lost, the client is stopped by the shutdown anyway
+            let _ = write_message(&mut stream, &Message::Close);
+            stream.shutdown(Shutdown::Both)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends the request over the current connection of the client.
+fn send_request(shared: &Shared, request: Request) -> Result<()> {
+    match shared.stream.lock().unwrap().as_mut() {
+        Some(stream) => write_message(stream, &Message::Request(request)),
+        None => Err(Error::Protocol("Client is closed".to_string())),
+    }
+}
+
+/// Connects, does the handshake and subscribes to the data.
+fn subscribe(
+    config: &ClientConfig,
+    replay_from: Option<u128>,
+    recording_id: Option<u64>,
+) -> Result<TcpStream> {
+    let mut stream = connect(config)?;
+    handshake(&mut stream)?;
+    write_message(
+        &mut stream,
+        &Message::Request(Request::Subscribe {
+            replay_from,
+            recording_id,
+        }),
+    )?;
+    Ok(stream)
+}
+
+fn connect(config: &ClientConfig) -> Result<TcpStream> {
+    let stream = match config.connect_timeout {
+        Some(timeout) => {
+            let addr = config
+                .address()
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| Error::Protocol(format!("Unknown server: {}", config.address())))?;
+            TcpStream::connect_timeout(&addr, timeout)?
+        }
+        None => TcpStream::connect(config.address())?,
+    };
+    stream.set_read_timeout(config.read_timeout)?;
+    Ok(stream)
+}
+
+/// Sends our `Hello` and checks that the server speaks the same protocol version and uses the
+/// same data type ids.
+fn handshake(stream: &mut TcpStream) -> Result<()> {
+    write_message(
+        stream,
+        &Message::Hello(PROTOCOL_VERSION, DATA_TYPE_ID_VERSION),
+    )?;
+    debug!("Sent Hello, awaiting reply...");
+    match read_message(stream)? {
+        Message::Hello(PROTOCOL_VERSION, DATA_TYPE_ID_VERSION) => {
+            debug!("Reply is ok!");
+            Ok(())
+        }
+        Message::Hello(PROTOCOL_VERSION, id_version) => Err(Error::Protocol(format!(
+            "Unsupported data type id version {}, the client uses {}",
+            id_version, DATA_TYPE_ID_VERSION
+        ))),
+        Message::Hello(version, _) => Err(Error::Protocol(format!(
+            "Unsupported protocol version {}, the client speaks {}",
+            version, PROTOCOL_VERSION
+        ))),
+        Message::Error(e) => Err(Error::Protocol(e)),
+        m => Err(Error::Protocol(format!("Expected Hello, got {:?}", m))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{CustomData, DataType};
+    use crate::{Annotation, Command};
+    use std::net::TcpListener;
+
+    fn record(t: u128) -> Data {
+        Data::RecordData(t, vec![])
+    }
+
+    fn command(t: u128) -> Data {
+        Data::Command(t, Command::DriveDist { dist: 10 })
+    }
+
+    /// Feeds the entries through `Resume` like `queue_new` and returns the ones that are new.
+    fn new_entries(resume: &mut Resume, data: Vec<Data>) -> Vec<Data> {
+        let mut new = vec![];
+        for d in data {
+            if !resume.already_received(&d) {
+                resume.received(&d);
+                new.push(d);
+            }
+        }
+        new
+    }
+
+    #[test]
+    fn replayed_entries_are_skipped_after_a_reconnect() {
+        let mut resume = Resume::default();
+        resume.recording(1);
+        let received = vec![record(1), command(1), record(2), command(2), record(2)];
+        assert_eq!(new_entries(&mut resume, received.clone()), received);
+        assert_eq!(resume.last_time, Some(2));
+
+        // the server replays from the last record before 2
+        resume.skip = resume.tail;
+        assert!(!resume.recording(1));
+        let replayed = vec![command(1), record(2), command(2), record(2), record(3)];
+        assert_eq!(new_entries(&mut resume, replayed), vec![record(3)]);
+    }
+
+    #[test]
+    fn changed_history_is_not_skipped() {
+        let mut resume = Resume::default();
+        new_entries(&mut resume, vec![record(1), record(2), record(2)]);
+        resume.skip = resume.tail;
+        // the server has only one of the records at 2 anymore
+        assert_eq!(
+            new_entries(&mut resume, vec![record(2), record(3)]),
+            vec![record(3)]
+        );
+    }
+
+    #[test]
+    fn nothing_is_skipped_after_the_recording_changed() {
+        let mut resume = Resume::default();
+        resume.recording(1);
+        let annotation = Data::Annotation(1, Annotation::new("a"));
+        new_entries(&mut resume, vec![record(1), annotation, record(5)]);
+        resume.skip = resume.tail;
+
+        // the data was cleared while we were disconnected, the timestamps restart at 0
+        assert!(resume.recording(2));
+        assert_eq!(resume.last_time, None);
+        let replayed = vec![record(0), record(1), record(5)];
+        assert_eq!(new_entries(&mut resume, replayed.clone()), replayed);
+    }
+
+    #[test]
+    fn undecodable_data_is_reported_without_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            assert_eq!(
+                read_message(&mut stream).unwrap(),
+                Message::Hello(PROTOCOL_VERSION, DATA_TYPE_ID_VERSION)
+            );
+            write_message(
+                &mut stream,
+                &Message::Hello(PROTOCOL_VERSION, DATA_TYPE_ID_VERSION),
+            )
+            .unwrap();
+            read_message(&mut stream).unwrap();
+            // a custom channel the client does not know
+            let unknown = DataType::Custom(CustomData {
+                channel: 200,
+                ..CustomData::default()
+            });
+            write_message(
+                &mut stream,
+                &Message::Data(vec![Data::RecordData(1, vec![unknown])]),
+            )
+            .unwrap();
+            write_message(&mut stream, &Message::Data(vec![record(2)])).unwrap();
+            write_message(&mut stream, &Message::Close).unwrap();
+        });
+        let config = ClientConfig {
+            port,
+            ..ClientConfig::new("127.0.0.1".to_string())
+        };
+        let events = Client::connect(config, None)
+            .unwrap()
+            .collect::<Vec<ClientEvent>>();
+        server.join().unwrap();
+        assert!(
+            matches!(events[0], ClientEvent::ServerError(_)),
+            "{:?}",
+            events
+        );
+        assert_eq!(
+            events[1..],
+            [ClientEvent::Data(record(2)), ClientEvent::Closed]
+        );
     }
-    println!("Terminated.");
-    thread_sender
-        .send("Terminated".to_string())
-        .expect("Couldn't send to main thread");
-    CLIENT.store(false, std::sync::atomic::Ordering::SeqCst);
 }
@@ -0,0 +1,284 @@
+use crate::{Error, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Port used when none is configured.
+pub const DEFAULT_PORT: u16 = 3333;
+
+/// What the server does when the send queue of a client is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the oldest queued record to make room for the new one
+    DropOldest,
+    /// Drop the new record
+    DropNewest,
+    /// Drop every second queued record, so the queue still covers the whole time span
+    Downsample,
+    /// Block the recording until the client has received the queued data
+    Block,
+}
+
+impl FromStr for DropPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "oldest" => Ok(DropPolicy::DropOldest),
+            "newest" => Ok(DropPolicy::DropNewest),
+            "downsample" => Ok(DropPolicy::Downsample),
+            "block" => Ok(DropPolicy::Block),
+            _ => Err(Error::Parse(format!("Unknown drop policy: {}", s))),
+        }
+    }
+}
+
+/// Configuration of the server on the robot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// Address the server binds to, "0.0.0.0" to accept connections on all interfaces
+    pub bind_address: String,
+    pub port: u16,
+    /// How long the accept loop sleeps when no client is waiting
+    pub accept_interval: Duration,
+    /// How many records can be queued for a client before the `drop_policy` applies
+    pub queue_capacity: usize,
+    pub drop_policy: DropPolicy,
+    /// How long a connection may be idle before the server sends a `Heartbeat`
+    pub heartbeat_interval: Duration,
+    /// How long to wait for any message from a client before it is considered dead and its
+    /// slot is freed, should be longer than the heartbeat interval of the clients
+    pub peer_timeout: Duration,
+    /// Directory the files of `Request::WriteData` are written to, clients can only choose
+    /// the file name
+    pub write_dir: PathBuf,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0".to_string(),
+            port: DEFAULT_PORT,
+            accept_interval: Duration::from_millis(100),
+            queue_capacity: 10_000,
+            drop_policy: DropPolicy::DropOldest,
+            heartbeat_interval: Duration::from_secs(1),
+            peer_timeout: Duration::from_secs(5),
+            write_dir: PathBuf::from("."),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.bind_address, self.port)
+    }
+
+    /// Parses the command line options of the server binary:
+    /// `--bind <address>`, `--port <port>`, `--accept <ms>`, `--queue <capacity>`,
+    /// `--drop-policy <oldest|newest|downsample|block>`, `--heartbeat <ms>`,
+    /// `--peer-timeout <ms>` and `--write-dir <directory>`.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut config = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--bind" => config.bind_address = value(&arg, args.next())?,
+                "--port" => config.port = parse(&arg, args.next())?,
+                "--accept" => {
+                    config.accept_interval = Duration::from_millis(parse(&arg, args.next())?)
+                }
+                "--queue" => config.queue_capacity = parse(&arg, args.next())?,
+                "--drop-policy" => config.drop_policy = value(&arg, args.next())?.parse()?,
+                "--heartbeat" => {
+                    config.heartbeat_interval = Duration::from_millis(parse(&arg, args.next())?)
+                }
+                "--peer-timeout" => {
+                    config.peer_timeout = Duration::from_millis(parse(&arg, args.next())?)
+                }
+                "--write-dir" => config.write_dir = PathBuf::from(value(&arg, args.next())?),
+                _ => return Err(Error::Parse(format!("Unknown option: {}", arg))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Configuration of a client connecting to the robot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientConfig {
+    /// Host name or address of the robot
+    pub server_name: String,
+    pub port: u16,
+    /// How long to wait for the connection, `None` uses the timeout of the system
+    pub connect_timeout: Option<Duration>,
+    /// How long to wait for any message from the server before it is considered dead,
+    /// should be longer than the heartbeat interval of the server. `None` waits forever.
+    pub read_timeout: Option<Duration>,
+    /// How long the client waits between two `Heartbeat`s to the server
+    pub heartbeat_interval: Duration,
+    /// How long to wait before the first reconnect after the connection was lost,
+    /// `None` disables reconnecting. The delay doubles after every failed attempt.
+    pub reconnect_delay: Option<Duration>,
+    /// Upper bound for the doubled reconnect delay
+    pub max_reconnect_delay: Duration,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            server_name: "localhost".to_string(),
+            port: DEFAULT_PORT,
+            connect_timeout: None,
+            read_timeout: Some(Duration::from_secs(5)),
+            heartbeat_interval: Duration::from_secs(1),
+            reconnect_delay: Some(Duration::from_millis(500)),
+            max_reconnect_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn new(server_name: String) -> Self {
+        Self {
+            server_name,
+            ..Self::default()
+        }
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.server_name, self.port)
+    }
+
+    /// Parses the command line options of the client binary: an optional server name followed by
+    /// `--port <port>`, `--connect-timeout <ms>`, `--read-timeout <ms>` (0 waits forever),
+    /// `--heartbeat <ms>`, `--reconnect-delay <ms>` (0 disables reconnecting) and
+    /// `--max-reconnect-delay <ms>`.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut config = Self::default();
+        let mut args = args.into_iter().peekable();
+        if let Some(server_name) = args.next_if(|a| !a.starts_with("--")) {
+            config.server_name = server_name;
+        }
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--port" => config.port = parse(&arg, args.next())?,
+                "--connect-timeout" => {
+                    config.connect_timeout = Some(Duration::from_millis(parse(&arg, args.next())?))
+                }
+                "--read-timeout" => {
+                    config.read_timeout = match parse(&arg, args.next())? {
+                        0 => None,
+                        ms => Some(Duration::from_millis(ms)),
+                    }
+                }
+                "--heartbeat" => {
+                    config.heartbeat_interval = Duration::from_millis(parse(&arg, args.next())?)
+                }
+                "--reconnect-delay" => {
+                    config.reconnect_delay = match parse(&arg, args.next())? {
+                        0 => None,
+                        ms => Some(Duration::from_millis(ms)),
+                    }
+                }
+                "--max-reconnect-delay" => {
+                    config.max_reconnect_delay = Duration::from_millis(parse(&arg, args.next())?)
+                }
+                _ => return Err(Error::Parse(format!("Unknown option: {}", arg))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn value(option: &str, value: Option<String>) -> Result<String> {
+    value.ok_or_else(|| Error::Parse(format!("Missing value for {}", option)))
+}
+
+fn parse<T: FromStr>(option: &str, v: Option<String>) -> Result<T> {
+    let v = value(option, v)?;
+    v.parse()
+        .map_err(|_| Error::Parse(format!("Invalid value for {}: {}", option, v)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn server_options_are_parsed() {
+        let config = ServerConfig::from_args(args(
+            "--bind 127.0.0.1 --port 4000 --queue 5 --drop-policy block --peer-timeout 200 \
+             --write-dir logs",
+        ))
+        .unwrap();
+        assert_eq!(
+            config,
+            ServerConfig {
+                bind_address: "127.0.0.1".to_string(),
+                port: 4000,
+                queue_capacity: 5,
+                drop_policy: DropPolicy::Block,
+                peer_timeout: Duration::from_millis(200),
+                write_dir: PathBuf::from("logs"),
+                ..ServerConfig::default()
+            }
+        );
+        assert_eq!(
+            ServerConfig::from_args(args("")).unwrap(),
+            ServerConfig::default()
+        );
+    }
+
+    #[test]
+    fn client_options_are_parsed() {
+        let config =
+            ClientConfig::from_args(args("robot --port 4000 --connect-timeout 300")).unwrap();
+        assert_eq!(
+            config,
+            ClientConfig {
+                server_name: "robot".to_string(),
+                port: 4000,
+                connect_timeout: Some(Duration::from_millis(300)),
+                ..ClientConfig::default()
+            }
+        );
+        // the server name is optional
+        let config = ClientConfig::from_args(args("--heartbeat 50")).unwrap();
+        assert_eq!(config.server_name, "localhost");
+        assert_eq!(config.heartbeat_interval, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn zero_disables_read_timeout_and_reconnecting() {
+        let config = ClientConfig::from_args(args("--read-timeout 0 --reconnect-delay 0")).unwrap();
+        assert_eq!(config.read_timeout, None);
+        assert_eq!(config.reconnect_delay, None);
+        let config =
+            ClientConfig::from_args(args("--read-timeout 20 --reconnect-delay 30")).unwrap();
+        assert_eq!(config.read_timeout, Some(Duration::from_millis(20)));
+        assert_eq!(config.reconnect_delay, Some(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn unknown_options_and_missing_or_invalid_values_are_errors() {
+        for server_args in ["--verbose", "--port", "--port x", "--drop-policy all"] {
+            assert!(
+                ServerConfig::from_args(args(server_args)).is_err(),
+                "{}",
+                server_args
+            );
+        }
+        for client_args in ["robot extra", "--verbose", "robot --heartbeat", "--port -1"] {
+            assert!(
+                ClientConfig::from_args(args(client_args)).is_err(),
+                "{}",
+                client_args
+            );
+        }
+    }
+}
@@ -1,163 +1,693 @@
-use serde::{Deserialize, Serialize};
-use strum_macros::FromRepr;
-
-#[derive(Debug, Serialize, Deserialize, PartialEq, FromRepr, Clone, Copy)]
-// todo: make macros for the write and from_string functions
-#[repr(u8)]
-pub enum DataType {
-    // length of data that should be there
-    // todo: fix this, its a giant mess
-    None(u8),
+use crate::error::{Error, Result};
+use crate::server::send_metadata;
+use crate::{header_metadata, SERVER};
+use serde::de::{SeqAccess, Visitor};
+use serde::ser::SerializeTuple;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::RwLock;
+
+/// Version of the id to data type mapping, bump this whenever an id is changed or retired.
+/// Ids of removed data types must never be reused.
+pub const DATA_TYPE_ID_VERSION: u8 = 2;
+
+/// Id of `DataType::Custom` on the wire, all custom channels share it.
+pub const CUSTOM_ID: u8 = 255;
+/// Column of the first custom channel in written files. The built-in data types use their id as
+/// column and must stay below it, so adding one never moves the custom columns.
+pub const CUSTOM_COLUMN_OFFSET: u8 = 128;
+
+/// Maximum amount of fields a custom channel can have.
+pub const MAX_CUSTOM_FIELDS: usize = 8;
+
+/// The numeric type of the fields of a custom channel, it decides how the values are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NumberType {
+    Int,
+    Float,
+}
+
+impl Display for NumberType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NumberType::Int => write!(f, "int"),
+            NumberType::Float => write!(f, "float"),
+        }
+    }
+}
+
+impl FromStr for NumberType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "int" => Ok(NumberType::Int),
+            "float" => Ok(NumberType::Float),
+            _ => Err(Error::Parse(format!("Unknown number type: {}", s))),
+        }
+    }
+}
+
+/// A named channel registered by the user with `register_custom_channel`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CustomChannel {
+    pub name: String,
+    pub fields: Vec<String>,
+    pub number_type: NumberType,
+}
+
+/// Written like "grabber(force, angle): float", this is how the definition is sent to the
+/// clients and written to the header of files, see `register_custom_channels`.
+impl Display for CustomChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}({}): {}",
+            self.name,
+            self.fields.join(", "),
+            self.number_type
+        )
+    }
+}
+
+impl FromStr for CustomChannel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Parse(format!("Invalid custom channel: {}", s));
+        let (channel, number_type) = s.rsplit_once("): ").ok_or_else(invalid)?;
+        let (name, fields) = channel.split_once('(').ok_or_else(invalid)?;
+        let fields = fields.split(", ").collect::<Vec<&str>>();
+        check_channel(name, &fields)?;
+        Ok(CustomChannel {
+            name: name.to_string(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            number_type: number_type.parse()?,
+        })
+    }
+}
+
+/// The values of a custom channel, only the first `fields.len()` values of the channel are used.
+/// Create it with `DataType::custom`, which checks that the channel is registered.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+pub struct CustomData {
+    pub(crate) channel: u8,
+    pub(crate) values: [f64; MAX_CUSTOM_FIELDS],
+}
+
+static CUSTOM_CHANNELS: RwLock<Vec<CustomChannel>> = RwLock::new(Vec::new());
+
+/// Fails if the name or a field is empty or contains one of ",;()" or a line break, because the
+/// channel could not be read back from the header of a file, or if the amount of fields is invalid.
+fn check_channel(name: &str, fields: &[&str]) -> Result<()> {
+    let invalid = |s: &str| s.trim().is_empty() || s.contains([',', ';', '(', ')', '\n', '\r']);
+    if invalid(name) {
+        return Err(Error::CustomChannel(format!("Invalid channel name: {:?}", name)));
+    }
+    if let Some(field) = fields.iter().find(|f| invalid(f)) {
+        return Err(Error::CustomChannel(format!(
+            "Invalid field name for {}: {:?}",
+            name, field
+        )));
+    }
+    if fields.is_empty() || fields.len() > MAX_CUSTOM_FIELDS {
+        return Err(Error::CustomChannel(format!(
+            "{} must have between 1 and {} fields, got {}",
+            name,
+            MAX_CUSTOM_FIELDS,
+            fields.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Registers a custom channel and returns its id, which is used to create the data with `DataType::custom`.
+///
+/// The ids are given out in the order of registration. The definitions are sent to the clients
+/// and written to the header of files, which register them when they read the data, see
+/// `register_custom_channels`. The name and the fields must not be empty or contain one of
+/// ",;()" or a line break.
+pub fn register_custom_channel(
+    name: &str,
+    fields: &[&str],
+    number_type: NumberType,
+) -> Result<u8> {
+    check_channel(name, fields)?;
+    let mut channels = CUSTOM_CHANNELS.write().expect("RwLock poisoned");
+    if channels.iter().any(|c| c.name == name) {
+        return Err(Error::CustomChannel(format!("{} is already registered", name)));
+    }
+    if channels.len() >= (u8::MAX - CUSTOM_COLUMN_OFFSET) as usize {
+        return Err(Error::CustomChannel("Too many custom channels".to_string()));
+    }
+    channels.push(CustomChannel {
+        name: name.to_string(),
+        fields: fields.iter().map(|f| f.to_string()).collect(),
+        number_type,
+    });
+    let id = (channels.len() - 1) as u8;
+    drop(channels);
+    // the clients need the definition before the first data of the channel
+    if SERVER.load(SeqCst) {
+        send_metadata(header_metadata());
+    }
+    Ok(id)
+}
+
+/// Registers the custom channels of the other side of a connection or of a file, in their order,
+/// so their ids match. Channels that are already registered with the same id are kept.
+/// Fails if another channel has one of the ids here.
+pub fn register_custom_channels(channels: &[CustomChannel]) -> Result<()> {
+    let mut registered = CUSTOM_CHANNELS.write().expect("RwLock poisoned");
+    for (id, channel) in channels.iter().enumerate() {
+        match registered.get(id) {
+            Some(c) if c == channel => {}
+            Some(c) => {
+                return Err(Error::CustomChannel(format!(
+                    "Custom channel {} is {} here, but {} on the other side",
+                    id, c, channel
+                )))
+            }
+            None => {
+                let fields = channel
+                    .fields
+                    .iter()
+                    .map(|f| f.as_str())
+                    .collect::<Vec<&str>>();
+                check_channel(&channel.name, &fields)?;
+                if registered.iter().any(|c| c.name == channel.name) {
+                    return Err(Error::CustomChannel(format!(
+                        "{} is registered with another id here",
+                        channel.name
+                    )));
+                }
+                registered.push(channel.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns all registered custom channels, their index is their id.
+pub fn custom_channels() -> Vec<CustomChannel> {
+    CUSTOM_CHANNELS.read().expect("RwLock poisoned").clone()
+}
+
+/// Returns the custom channel with the given id, if it is registered.
+pub fn custom_channel(channel: u8) -> Option<CustomChannel> {
+    CUSTOM_CHANNELS
+        .read()
+        .expect("RwLock poisoned")
+        .get(channel as usize)
+        .cloned()
+}
+
+/// Returns the id of the custom channel with the given name, if it is registered.
+pub fn custom_channel_id(name: &str) -> Option<u8> {
+    CUSTOM_CHANNELS
+        .read()
+        .expect("RwLock poisoned")
+        .iter()
+        .position(|c| c.name == name)
+        .map(|i| i as u8)
+}
+
+/// Parses the next field of a data type, `name` is only used for the error message.
+fn parse_field<'a, T>(parts: &mut impl Iterator<Item = &'a &'a str>, name: &str) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let field = parts
+        .next()
+        .ok_or_else(|| Error::Parse(format!("Missing field for {}", name)))?;
+    field
+        .parse::<T>()
+        .map_err(|e| Error::Parse(format!("Invalid field {} for {}: {}", field, name, e)))
+}
+
+/// Returns the id after the highest id in `ids`.
+const fn next_id(ids: &[u8]) -> u8 {
+    let mut max = 0;
+    let mut i = 0;
+    while i < ids.len() {
+        if ids[i] > max {
+            max = ids[i];
+        }
+        i += 1;
+    }
+    max + 1
+}
+
+/// Checks that no id is 0 or used twice.
+const fn valid_ids(ids: &[u8]) -> bool {
+    let mut i = 0;
+    while i < ids.len() {
+        if ids[i] == 0 {
+            return false;
+        }
+        let mut j = i + 1;
+        while j < ids.len() {
+            if ids[i] == ids[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Defines the `DataType` enum and generates all the tables that depend on the fields of the variants.
+///
+/// Every variant is declared once as `id: Name(types) { pattern } => [field => "description", ...]`.
+/// The pattern binds every field by its name and is also used to construct the variant again,
+/// so it has to mirror the types (see `RGB`). The id is the stable channel id of the data type,
+/// it is used as the column in written files and on the wire, see `DATA_TYPE_ID_VERSION`.
+/// `None` (id 0) and `Custom` (id `CUSTOM_ID`) are always added as the first and last variant.
+macro_rules! data_types {
+    ($(
+        $(#[$meta:meta])*
+        $id:literal: $variant:ident($($ty:ty),+) { $($pat:tt)+ } => [$($field:ident => $desc:literal),+ $(,)?]
+    ),+ $(,)?) => {
+        /// Ids of all built-in data types except `None`.
+        const BUILTIN_IDS: &[u8] = &[$($id),+];
+        const _: () = assert!(valid_ids(BUILTIN_IDS), "data type ids must be unique and not 0");
+        const _: () = assert!(next_id(BUILTIN_IDS) <= CUSTOM_COLUMN_OFFSET, "data type ids must be below CUSTOM_COLUMN_OFFSET");
+
+        #[derive(Debug, PartialEq, Clone, Copy)]
+        pub enum DataType {
+            /// Placeholder for a column without data, the value is the column
+            None(u8),
+            $($(#[$meta])* $variant($($ty),+),)+
+            /// a channel registered with `register_custom_channel`
+            Custom(CustomData),
+        }
+
+        impl DataType {
+            /// Returns the stable channel id of this data type, custom channels all share `CUSTOM_ID`.
+            pub fn id(&self) -> u8 {
+                match self {
+                    DataType::None(_) => 0,
+                    $(DataType::$variant(..) => $id,)+
+                    DataType::Custom(_) => CUSTOM_ID,
+                }
+            }
+
+            /// Returns the data type with the given id and default values, like `FromRepr` would.
+            pub fn from_id(id: u8) -> Option<DataType> {
+                match id {
+                    0 => Some(DataType::None(0)),
+                    $($id => {
+                        let ($($pat)+,): ($($ty,)+) = Default::default();
+                        Some(DataType::$variant($($pat)+))
+                    })+
+                    CUSTOM_ID => Some(DataType::Custom(CustomData::default())),
+                    _ => None,
+                }
+            }
+
+            pub fn write(&self) -> String {
+                match self {
+                    // maybe we should use something like "n" instead of "null" to save space
+                    DataType::None(u8) => vec!["null".to_string(); Self::get_none(*u8) as usize].join(", "),
+                    $(DataType::$variant($($pat)+) => [$($field.to_string()),+].join(", "),)+
+                    DataType::Custom(c) => c.write(),
+                }
+            }
+
+            /// Returns the amount of fields of this data type.
+            pub fn none(&self) -> u8 {
+                match self {
+                    DataType::None(u8) => Self::get_none(*u8),
+                    $(DataType::$variant(..) => [$(stringify!($field)),+].len() as u8,)+
+                    DataType::Custom(c) => Self::get_none(CUSTOM_COLUMN_OFFSET + c.channel),
+                }
+            }
+
+            /// Returns the names of the fields of this data type.
+            pub fn field_names(&self) -> Vec<String> {
+                match self {
+                    DataType::None(_) => vec![],
+                    $(DataType::$variant(..) => vec![$(stringify!($field).to_string()),+],)+
+                    DataType::Custom(c) => custom_channel(c.channel)
+                        .map(|c| c.fields)
+                        .unwrap_or_default(),
+                }
+            }
+
+            /// Writes the description of the data type like this: for Color: "right color, left color"
+            pub fn write_description(&self) -> String {
+                match self {
+                    DataType::None(_) => String::new(),
+                    $(DataType::$variant(..) => [$($desc),+].join(", "),)+
+                    DataType::Custom(c) => {
+                        Self::column_description(CUSTOM_COLUMN_OFFSET + c.channel).unwrap_or_default()
+                    }
+                }
+            }
+
+            /// Deserializes the fields of the data type with the given id from the rest of the sequence.
+            fn deserialize_fields<'de, A: SeqAccess<'de>>(id: u8, mut seq: A) -> std::result::Result<DataType, A::Error> {
+                use serde::de::Error;
+                let missing = || A::Error::custom(format!("Missing fields for data type {}", id));
+                Ok(match id {
+                    0 => DataType::None(seq.next_element()?.ok_or_else(missing)?),
+                    $($id => {
+                        let ($($pat)+,): ($($ty,)+) = seq.next_element()?.ok_or_else(missing)?;
+                        DataType::$variant($($pat)+)
+                    })+
+                    CUSTOM_ID => {
+                        let data: CustomData = seq.next_element()?.ok_or_else(missing)?;
+                        // data of an unknown channel could not be written or described
+                        if custom_channel(data.channel).is_none() {
+                            return Err(A::Error::custom(format!("Unknown custom channel: {}", data.channel)));
+                        }
+                        DataType::Custom(data)
+                    }
+                    _ => return Err(A::Error::custom(format!("Unknown data type id: {}", id))),
+                })
+            }
+
+            /// Creates a built-in data type from its written fields.
+            fn builtin_from_fields(data: DataType, fields: &[&str]) -> Result<DataType> {
+                let mut parts = fields.iter();
+                Ok(match data {
+                    $(DataType::$variant(..) => {
+                        $(let $field = parse_field(&mut parts, stringify!($variant))?;)+
+                        DataType::$variant($($pat)+)
+                    })+
+                    DataType::None(_) | DataType::Custom(_) => {
+                        unreachable!("None and custom channels are not built-in")
+                    }
+                })
+            }
+        }
+
+        /// Data types are serialized as their id followed by their fields, so the wire format
+        /// only depends on the ids and not on the order of the variants.
+        impl Serialize for DataType {
+            fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+                let mut tuple = serializer.serialize_tuple(2)?;
+                tuple.serialize_element(&self.id())?;
+                match self {
+                    DataType::None(u8) => tuple.serialize_element(u8)?,
+                    $(DataType::$variant($($pat)+) => tuple.serialize_element(&($($pat)+,))?,)+
+                    DataType::Custom(c) => tuple.serialize_element(c)?,
+                }
+                tuple.end()
+            }
+        }
+    };
+}
+
+struct DataTypeVisitor;
+
+impl<'de> Visitor<'de> for DataTypeVisitor {
+    type Value = DataType;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a data type id followed by its fields")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<DataType, A::Error> {
+        let id: u8 = seq
+            .next_element()?
+            .ok_or_else(|| serde::de::Error::custom("Missing data type id"))?;
+        DataType::deserialize_fields(id, seq)
+    }
+}
+
+impl<'de> Deserialize<'de> for DataType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<DataType, D::Error> {
+        deserializer.deserialize_tuple(2, DataTypeVisitor)
+    }
+}
+
+data_types! {
     /// right, left
-    Color(i16, i16),
+    1: Color(i16, i16) { r, l } => [r => "right color", l => "left color"],
     // todo: track the whole distance in the same way as DrivenDistance does, or scrap this data type
-    Distance(i16),
+    2: Distance(i16) { d } => [d => "dist"],
     /// right, left
-    CalcSpeed(i16, i16),
+    3: CalcSpeed(i16, i16) { r, l } => [r => "right calculated v", l => "left calculated v"],
     /// right, left
-    SyncSpeed(i16, i16),
+    4: SyncSpeed(i16, i16) { r, l } => [r => "right synced v", l => "left synced v"],
     /// right, left
-    RealSpeeds(i16, i16),
+    5: RealSpeeds(i16, i16) { r, l } => [r => "right real v", l => "left real v"],
     /// right, left
-    DrivenDistance(f32, f32),
-    SyncError(f32),
+    6: DrivenDistance(f32, f32) { r, l } => [r => "right distance", l => "left distance"],
+    7: SyncError(f32) { e } => [e => "sync error"],
     /// right, left
-    Correction(f32, f32),
+    8: Correction(f32, f32) { r, l } => [r => "right correction", l => "left correction"],
     /// right, left
-    AverageSpeed(f32, f32),
+    9: AverageSpeed(f32, f32) { r, l } => [r => "right average speed", l => "left average speed"],
     /// right, left
-    RGB((i16, i16, i16), (i16, i16, i16)),
+    10: RGB((i16, i16, i16), (i16, i16, i16)) { (r, g, b), (r1, g1, b1) } => [
+        r => "right r",
+        g => "right g",
+        b => "right b",
+        r1 => "left r",
+        g1 => "left g",
+        b1 => "left b",
+    ],
     /// cur_speed, target_speed
-    CurTarSpeeds(i16, i16),
-    InstantDerivative(f32)
-    // todo: add custom data type for the user to use and define
+    11: CurTarSpeeds(i16, i16) { c, t } => [c => "current speed", t => "target speed"],
+    12: InstantDerivative(f32) { d } => [d => "instant derivative"],
+}
+
+impl CustomData {
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    /// Returns the values of the fields of the channel.
+    pub fn values(&self) -> &[f64] {
+        let len = custom_channel(self.channel).map_or(0, |c| c.fields.len());
+        &self.values[..len]
+    }
+
+    fn write(&self) -> String {
+        let channel = custom_channel(self.channel).expect("Custom channel not registered");
+        self.values[..channel.fields.len()]
+            .iter()
+            .map(|v| match channel.number_type {
+                NumberType::Int => format!("{}", *v as i64),
+                NumberType::Float => format!("{}", v),
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
 }
 
 impl DataType {
-    pub fn write(&self) -> String {
+    /// Creates the data for a custom channel, `values` must have as many values as the channel has fields.
+    pub fn custom(channel: u8, values: &[f64]) -> Result<DataType> {
+        let custom = custom_channel(channel)
+            .ok_or_else(|| Error::CustomChannel(format!("Unknown custom channel: {}", channel)))?;
+        if values.len() != custom.fields.len() {
+            return Err(Error::CustomChannel(format!(
+                "{} has {} fields, got {} values",
+                custom.name,
+                custom.fields.len(),
+                values.len()
+            )));
+        }
+        let mut data = CustomData {
+            channel,
+            ..CustomData::default()
+        };
+        data.values[..values.len()].copy_from_slice(values);
+        Ok(DataType::Custom(data))
+    }
+
+    /// Returns the column of this data type in a written file.
+    /// This is the id of the data type, custom channels start at `CUSTOM_COLUMN_OFFSET`.
+    pub fn column(&self) -> u8 {
         match self {
-            // maybe we should use something like "n" instead of "null" to save space
-            DataType::None(u8) => vec!["null".to_string(); Self::get_none(*u8) as usize].join(", "),
-            DataType::Color(r, l) => format!("{}, {}", r, l),
-            DataType::Distance(d) => format!("{}", d),
-            DataType::CalcSpeed(r, l) => format!("{}, {}", r, l),
-            DataType::SyncSpeed(r, l) => format!("{}, {}", r, l),
-            DataType::RealSpeeds(r, l) => format!("{}, {}", r, l),
-            DataType::DrivenDistance(r, l) => format!("{}, {}", r, l),
-            DataType::SyncError(e) => format!("{}", e),
-            DataType::Correction(r, l) => format!("{}, {}", r, l),
-            DataType::AverageSpeed(r, l) => format!("{}, {}", r, l),
-            DataType::RGB((r, g, b), (r1, g1, b1)) => {
-                format!("{}, {}, {}, {}, {}, {}", r, g, b, r1, g1, b1)
-            }
-            DataType::CurTarSpeeds(c, t) => format!("{}, {}", c, t),
-            DataType::InstantDerivative(d) => format!("{}", d)
+            DataType::Custom(c) => CUSTOM_COLUMN_OFFSET + c.channel,
+            _ => self.id(),
         }
     }
 
+    /// Returns all columns that can be written: the built-in data types and the registered custom channels.
+    pub fn columns() -> Vec<u8> {
+        let custom = CUSTOM_CHANNELS.read().expect("RwLock poisoned").len() as u8;
+        let mut columns = BUILTIN_IDS.to_vec();
+        columns.sort();
+        columns.extend(CUSTOM_COLUMN_OFFSET..CUSTOM_COLUMN_OFFSET + custom);
+        columns
+    }
+
+    /// Returns the description of the column like `write_description`, if the column exists.
+    pub fn column_description(column: u8) -> Option<String> {
+        if column >= CUSTOM_COLUMN_OFFSET {
+            custom_channel(column - CUSTOM_COLUMN_OFFSET).map(|c| {
+                c.fields
+                    .iter()
+                    .map(|f| format!("{} {}", c.name, f))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            })
+        } else {
+            DataType::from_id(column).map(|d| d.write_description())
+        }
+    }
+
+    /// Returns the amount of fields of the column.
     pub fn get_none(i: u8) -> u8 {
-        match i {
-            0 => 0,
-            1 => 2,
-            2 => 1,
-            3 => 2,
-            4 => 2,
-            5 => 2,
-            6 => 2,
-            7 => 1,
-            8 => 2,
-            9 => 2,
-            10 => 6,
-            11 => 2,
-            12 => 1,
-            _ => panic!("Unknown data type: {}", i),
-        }
-    }
-
-    pub fn none(&self) -> u8 {
-        match self {
-            DataType::None(u8) => *u8,
-            DataType::Color(_, _) => 2,
-            DataType::Distance(_) => 1,
-            DataType::CalcSpeed(_, _) => 2,
-            DataType::SyncSpeed(_, _) => 2,
-            DataType::RealSpeeds(_, _) => 2,
-            DataType::DrivenDistance(_, _) => 2,
-            DataType::SyncError(_) => 1,
-            DataType::Correction(_, _) => 2,
-            DataType::AverageSpeed(_, _) => 2,
-            DataType::RGB(_, _) => 6,
-            DataType::CurTarSpeeds(_, _) => 2,
-            DataType::InstantDerivative(_) => 1
+        if i >= CUSTOM_COLUMN_OFFSET {
+            return match custom_channel(i - CUSTOM_COLUMN_OFFSET) {
+                Some(c) => c.fields.len() as u8,
+                None => panic!("Unknown data type: {}", i),
+            };
+        }
+        match DataType::from_id(i) {
+            Some(DataType::None(_)) => 0,
+            Some(d) => d.none(),
+            None => panic!("Unknown data type: {}", i),
         }
     }
 
+    /// Same as `id`, kept for code that used the old discriminant access.
     pub fn to_u8(&self) -> u8 {
-        unsafe { (self as *const DataType as *const u8).read() }
+        self.id()
+    }
+
+    /// Same as `from_id`, kept for code that used the `FromRepr` lookup.
+    pub fn from_repr(id: u8) -> Option<DataType> {
+        Self::from_id(id)
     }
 
     /// This converts a string in this format "1, 30, 30" to a DataType::Color(30, 30)
     pub fn from_string(s: String) -> DataType {
+        Self::try_from_string(s).unwrap()
+    }
+
+    /// Same as `from_string`, but returns an error instead of panicking if the string is malformed.
+    pub fn try_from_string(s: String) -> Result<DataType> {
         let mut parts = s.split(", ");
-        let ty = parts.next().unwrap();
-        let data = match DataType::from_repr(ty.parse::<u8>().unwrap()) {
-            Some(d) => d,
-            None => panic!("Unknown data type: {}", ty),
-        };
-        macro_rules! ty1 {
-            ($name:ident, $ty:ty) => {{
-                let r = parts.next().unwrap().parse::<$ty>().unwrap();
-                DataType::$name(r)
-            }};
-        }
-        macro_rules! ty2 {
-            ($name:ident, $ty:ty) => {{
-                let r = parts.next().unwrap().parse::<$ty>().unwrap();
-                let l = parts.next().unwrap().parse::<$ty>().unwrap();
-                DataType::$name(r, l)
-            }};
-        }
-        match data {
-            DataType::None(_) => DataType::None(0),
-            DataType::Color(_, _) => ty2!(Color, i16),
-            DataType::Distance(_) => ty1!(Distance, i16),
-            DataType::CalcSpeed(_, _) => ty2!(CalcSpeed, i16),
-            DataType::SyncSpeed(_, _) => ty2!(SyncSpeed, i16),
-            DataType::RealSpeeds(_, _) => ty2!(RealSpeeds, i16),
-            DataType::DrivenDistance(_, _) => ty2!(DrivenDistance, f32),
-            DataType::SyncError(_) => ty1!(SyncError, f32),
-            DataType::Correction(_, _) => ty2!(Correction, f32),
-            DataType::AverageSpeed(_, _) => ty2!(AverageSpeed, f32),
-            DataType::RGB(_, _) => {
-                let r = parts.next().unwrap().parse::<i16>().unwrap();
-                let g = parts.next().unwrap().parse::<i16>().unwrap();
-                let b = parts.next().unwrap().parse::<i16>().unwrap();
-                let r1 = parts.next().unwrap().parse::<i16>().unwrap();
-                let g1 = parts.next().unwrap().parse::<i16>().unwrap();
-                let b1 = parts.next().unwrap().parse::<i16>().unwrap();
-                DataType::RGB((r, g, b), (r1, g1, b1))
+        let ty = parts.next().unwrap_or_default();
+        let column = ty
+            .parse::<u8>()
+            .map_err(|e| Error::Parse(format!("Invalid data type {}: {}", ty, e)))?;
+        Self::try_from_fields(column, &parts.collect::<Vec<&str>>())
+    }
+
+    /// Creates the data type of the given column (see `column`) from its written fields.
+    pub fn try_from_fields(column: u8, fields: &[&str]) -> Result<DataType> {
+        if column >= CUSTOM_COLUMN_OFFSET {
+            let channel = column - CUSTOM_COLUMN_OFFSET;
+            let values = fields
+                .iter()
+                .map(|f| {
+                    f.parse::<f64>()
+                        .map_err(|e| Error::Parse(format!("Invalid field {}: {}", f, e)))
+                })
+                .collect::<Result<Vec<f64>>>()?;
+            return DataType::custom(channel, &values);
+        }
+        match DataType::from_id(column) {
+            // None is only a placeholder, it is never written as a column
+            Some(DataType::None(_)) | None => {
+                Err(Error::Parse(format!("Unknown data type: {}", column)))
             }
-            DataType::CurTarSpeeds(_, _) => ty2!(CurTarSpeeds, i16),
-            DataType::InstantDerivative(_) => ty1!(InstantDerivative, f32)
+            Some(d) => Self::builtin_from_fields(d, fields),
         }
     }
+}
 
-    /// Writes the description of the data type like this: for Color: "right color, left color"
-    pub fn write_description(&self) -> String {
-        match self {
-            DataType::None(_) => String::new(),
-            DataType::Color(_, _) => "right color, left color".to_string(),
-            DataType::Distance(_) => "dist".to_string(),
-            DataType::CalcSpeed(_, _) => "right calculated v, left calculated v".to_string(),
-            DataType::SyncSpeed(_, _) => "right synced v, left synced v".to_string(),
-            DataType::RealSpeeds(_, _) => "right real v, left real v".to_string(),
-            DataType::DrivenDistance(_, _) => "right distance, left distance".to_string(),
-            DataType::SyncError(_) => "sync error".to_string(),
-            DataType::Correction(_, _) => "right correction, left correction".to_string(),
-            DataType::AverageSpeed(_, _) => "right average speed, left average speed".to_string(),
-            DataType::RGB(_, _) => "right r, right g, right b, left r, left g, left b".to_string(),
-            DataType::CurTarSpeeds(_, _) => "current speed, target speed".to_string(),
-            DataType::InstantDerivative(_) => "instant derivative".to_string()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_and_duplicate_ids_are_invalid() {
+        assert!(valid_ids(&[3, 1, 2]));
+        assert!(!valid_ids(&[1, 0, 2]));
+        assert!(!valid_ids(&[1, 2, 1]));
+    }
+
+    #[test]
+    fn invalid_custom_channel_names_are_rejected() {
+        for (name, fields) in [
+            ("bad, name", &["x"][..]),
+            ("", &["x"][..]),
+            ("bad\nname", &["x"][..]),
+            ("valid_name", &["x, y"][..]),
+            ("valid_name", &[" "][..]),
+        ] {
+            assert!(
+                register_custom_channel(name, fields, NumberType::Int).is_err(),
+                "{:?} {:?}",
+                name,
+                fields
+            );
         }
+        assert!(custom_channel_id("valid_name").is_none());
+    }
+
+    #[test]
+    fn fields_of_none_are_rejected() {
+        assert!(DataType::try_from_fields(0, &[]).is_err());
+        assert!(DataType::try_from_string("0, 3".to_string()).is_err());
+        assert_eq!(
+            DataType::try_from_fields(1, &["30", "-2"]).unwrap(),
+            DataType::Color(30, -2)
+        );
+    }
+
+    #[test]
+    fn custom_data_of_unknown_channels_is_not_deserialized() {
+        let channel = register_custom_channel("deserialize_test", &["a", "b"], NumberType::Float)
+            .unwrap();
+        let data = DataType::custom(channel, &[1.5, -2.0]).unwrap();
+        let bytes = bincode::serialize(&data).unwrap();
+        assert_eq!(bincode::deserialize::<DataType>(&bytes).unwrap(), data);
+
+        let unknown = DataType::Custom(CustomData {
+            channel: 120,
+            ..CustomData::default()
+        });
+        let bytes = bincode::serialize(&unknown).unwrap();
+        assert!(bincode::deserialize::<DataType>(&bytes).is_err());
+    }
+
+    #[test]
+    fn custom_channels_are_written_and_parsed() {
+        let channel = CustomChannel {
+            name: "grabber arm".to_string(),
+            fields: vec!["force".to_string(), "angle".to_string()],
+            number_type: NumberType::Float,
+        };
+        assert_eq!(channel.to_string(), "grabber arm(force, angle): float");
+        assert_eq!(
+            channel.to_string().parse::<CustomChannel>().unwrap(),
+            channel
+        );
+        for invalid in [
+            "grabber(force): double",
+            "grabber: int",
+            "grabber(): int",
+            "(x): int",
+        ] {
+            assert!(invalid.parse::<CustomChannel>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn conflicting_custom_channels_are_rejected() {
+        let id = register_custom_channel("sync_test", &["x"], NumberType::Int).unwrap();
+        // the registered channels never change, so this prefix is the same for the whole test
+        let mut channels = custom_channels()[..=id as usize].to_vec();
+        register_custom_channels(&channels).unwrap();
+        channels[id as usize].number_type = NumberType::Float;
+        assert!(matches!(
+            register_custom_channels(&channels),
+            Err(Error::CustomChannel(_))
+        ));
+        assert_eq!(custom_channel(id).unwrap().number_type, NumberType::Int);
     }
 }
@@ -0,0 +1,90 @@
+use crate::data_types::DataType;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Errors that can occur while recording, writing, reading or sending data.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file or the connection failed
+    Io(std::io::Error),
+    /// A recorded file or a data type string could not be parsed
+    Parse(String),
+    /// The other side of the connection sent something we did not expect
+    Protocol(String),
+    /// The other side of the connection did not send anything for this long and is considered dead
+    Timeout(Duration),
+    /// `save_data` was called with the same data type multiple times
+    DuplicateDataType(DataType),
+    /// The index is not in the recorded data
+    IndexOutOfRange(usize),
+    /// A custom channel could not be registered or the data does not fit the channel
+    CustomChannel(String),
+    /// A custom command could not be registered or the parameters do not fit the command
+    CustomCommand(String),
+    /// The data type was not declared as a column when the `CsvWriter` was created
+    UndeclaredColumn(DataType),
+    /// The call is not allowed in the current state of the session, like changing the time unit
+    /// after the session started
+    Session(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Parse(s) => write!(f, "Parse error: {}", s),
+            Error::Protocol(s) => write!(f, "Protocol error: {}", s),
+            Error::Timeout(d) => write!(f, "Peer did not respond for {:?}", d),
+            Error::DuplicateDataType(d) => {
+                write!(f, "data contains the same DataType multiple times: {:?}", d)
+            }
+            Error::IndexOutOfRange(i) => write!(f, "Index out of range: {}", i),
+            Error::CustomChannel(s) => write!(f, "Custom channel error: {}", s),
+            Error::CustomCommand(s) => write!(f, "Custom command error: {}", s),
+            Error::UndeclaredColumn(d) => {
+                write!(f, "Column of {:?} was not declared for the writer", d)
+            }
+            Error::Session(s) => write!(f, "Session error: {}", s),
+        }
+    }
+}
+
+impl Error {
+    /// Turns an I/O error caused by a read or write timeout of `timeout` into `Timeout`.
+    pub(crate) fn or_timeout(self, timeout: Option<Duration>) -> Error {
+        match (self, timeout) {
+            (Error::Io(e), Some(timeout))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                Error::Timeout(timeout)
+            }
+            (e, _) => e,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Protocol(e.to_string())
+    }
+}
@@ -1,73 +1,695 @@
 // This module contains various data types, client and server related code.
+pub mod binary;
 pub mod client;
+pub mod config;
 pub mod data_types;
+pub mod error;
+pub mod protocol;
+pub mod reader;
+pub mod recorder;
 pub mod server;
+pub mod writer;
 
 // Importing necessary modules and libraries.
-use crate::server::add_data;
-use crate::Data::{RecordData, RecordDataOption};
-use data_types::DataType;
+use data_types::{custom_channels, register_custom_channels, CustomChannel, DataType};
+pub use error::{Error, Result};
+pub use recorder::Recorder;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Display};
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::str::FromStr;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
-use std::sync::Mutex;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
 use strum_macros::Display;
-use whoami::fallible;
 
 /// Enum representing the direction of movement.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
 pub enum Direction {
     Left,
     Right,
 }
 
+impl FromStr for Direction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "Left" => Ok(Direction::Left),
+            "Right" => Ok(Direction::Right),
+            _ => Err(Error::Parse(format!("Unknown direction: {}", s))),
+        }
+    }
+}
+
+/// The type of a parameter of a custom command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    Int,
+    Float,
+    Bool,
+    Text,
+}
+
+/// The value of a parameter of a custom command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ParamValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    /// Must not contain ',', '{', '}' or a line break, so it can be read back from a file
+    Text(String),
+}
+
+impl ParamValue {
+    pub fn param_type(&self) -> ParamType {
+        match self {
+            ParamValue::Int(_) => ParamType::Int,
+            ParamValue::Float(_) => ParamType::Float,
+            ParamValue::Bool(_) => ParamType::Bool,
+            ParamValue::Text(_) => ParamType::Text,
+        }
+    }
+
+    /// Parses a value written by `Display`, `name` is only used for the error message.
+    fn parse(s: &str, param_type: ParamType, name: &str) -> Result<Self> {
+        let invalid =
+            |e: &dyn Display| Error::Parse(format!("Invalid parameter {}: {}: {}", name, s, e));
+        Ok(match param_type {
+            ParamType::Int => ParamValue::Int(s.parse().map_err(|e| invalid(&e))?),
+            ParamType::Float => ParamValue::Float(s.parse().map_err(|e| invalid(&e))?),
+            ParamType::Bool => ParamValue::Bool(s.parse().map_err(|e| invalid(&e))?),
+            ParamType::Text => ParamValue::Text(s.to_string()),
+        })
+    }
+}
+
+impl Display for ParamValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParamValue::Int(i) => write!(f, "{}", i),
+            ParamValue::Float(x) => write!(f, "{}", x),
+            ParamValue::Bool(b) => write!(f, "{}", b),
+            ParamValue::Text(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// A command registered by the user with `register_custom_command`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomCommand {
+    pub name: String,
+    pub params: Vec<(String, ParamType)>,
+}
+
+static CUSTOM_COMMANDS: RwLock<Vec<CustomCommand>> = RwLock::new(Vec::new());
+
+/// Names of the built-in commands, they can't be registered as custom commands.
+const BUILTIN_COMMANDS: [&str; 7] = [
+    "Turn",
+    "TurnRadius",
+    "DriveDist",
+    "DriveLine",
+    "AlignDist",
+    "AlignLine",
+    "TurnOneWheel",
+];
+
+/// Registers a custom command like a grabber or lift action, which is then created with
+/// `Command::custom`.
+///
+/// The names of the command and its parameters may only contain letters, digits and '_'.
+/// Files with custom commands can only be read by a program that registered the same commands.
+pub fn register_custom_command(name: &str, params: &[(&str, ParamType)]) -> Result<()> {
+    let is_identifier =
+        |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_identifier(name) || BUILTIN_COMMANDS.contains(&name) {
+        return Err(Error::CustomCommand(format!(
+            "Invalid command name: {}",
+            name
+        )));
+    }
+    for (i, (param, _)) in params.iter().enumerate() {
+        if !is_identifier(param) || params[..i].iter().any(|(p, _)| p == param) {
+            return Err(Error::CustomCommand(format!(
+                "Invalid parameter name for {}: {}",
+                name, param
+            )));
+        }
+    }
+    let mut commands = CUSTOM_COMMANDS.write().expect("RwLock poisoned");
+    if commands.iter().any(|c| c.name == name) {
+        return Err(Error::CustomCommand(format!(
+            "{} is already registered",
+            name
+        )));
+    }
+    commands.push(CustomCommand {
+        name: name.to_string(),
+        params: params.iter().map(|(p, t)| (p.to_string(), *t)).collect(),
+    });
+    Ok(())
+}
+
+/// Returns the custom command with the given name, if it is registered.
+pub fn custom_command(name: &str) -> Option<CustomCommand> {
+    CUSTOM_COMMANDS
+        .read()
+        .expect("RwLock poisoned")
+        .iter()
+        .find(|c| c.name == name)
+        .cloned()
+}
+
 /// Enum representing various types of commands.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Command {
-    Turn(i16),
-    // radius, angle
-    TurnRadius(i16, i16),
-    DriveDist(i16),
-    DriveLine(i16),
-    AlignDist(i16),
-    AlignLine(i16),
-    TurnOneWheel(i16, Direction),
+    Turn {
+        angle: i16,
+    },
+    TurnRadius {
+        radius: i16,
+        angle: i16,
+    },
+    DriveDist {
+        dist: i16,
+    },
+    DriveLine {
+        dist: i16,
+    },
+    AlignDist {
+        dist: i16,
+    },
+    AlignLine {
+        dist: i16,
+    },
+    TurnOneWheel {
+        angle: i16,
+        direction: Direction,
+    },
+    /// A command registered with `register_custom_command`, create it with `Command::custom`
+    Custom {
+        name: String,
+        params: Vec<(String, ParamValue)>,
+    },
 }
 
 // Implementing Display trait for Command enum.
 impl Display for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Command::Turn(a) => write!(f, "Turn({})", a),
-            Command::TurnRadius(r, a) => write!(f, "TurnRadius({}, {})", r, a),
-            Command::DriveDist(d) => write!(f, "DriveDist({})", d),
-            Command::DriveLine(d) => write!(f, "DriveLine({})", d),
-            Command::AlignDist(d) => write!(f, "AlignDist({})", d),
-            Command::AlignLine(d) => write!(f, "AlignLine({})", d),
-            Command::TurnOneWheel(a, d) => write!(f, "TurnOneWheel({}, {})", a, d),
+            Command::Turn { angle } => write!(f, "Turn({})", angle),
+            Command::TurnRadius { radius, angle } => write!(f, "TurnRadius({}, {})", radius, angle),
+            Command::DriveDist { dist } => write!(f, "DriveDist({})", dist),
+            Command::DriveLine { dist } => write!(f, "DriveLine({})", dist),
+            Command::AlignDist { dist } => write!(f, "AlignDist({})", dist),
+            Command::AlignLine { dist } => write!(f, "AlignLine({})", dist),
+            Command::TurnOneWheel { angle, direction } => {
+                write!(f, "TurnOneWheel({}, {})", angle, direction)
+            }
+            Command::Custom { name, params } => {
+                let values = params
+                    .iter()
+                    .map(|(_, v)| v.to_string())
+                    .collect::<Vec<String>>();
+                write!(f, "{}({})", name, values.join(", "))
+            }
+        }
+    }
+}
+
+impl Command {
+    /// Creates a custom command, `values` must have the types of the registered parameters.
+    pub fn custom(name: &str, values: Vec<ParamValue>) -> Result<Command> {
+        let custom = custom_command(name)
+            .ok_or_else(|| Error::CustomCommand(format!("Unknown custom command: {}", name)))?;
+        if values.len() != custom.params.len() {
+            return Err(Error::CustomCommand(format!(
+                "{} has {} parameters, got {} values",
+                name,
+                custom.params.len(),
+                values.len()
+            )));
+        }
+        for ((param, param_type), value) in custom.params.iter().zip(&values) {
+            if value.param_type() != *param_type {
+                return Err(Error::CustomCommand(format!(
+                    "Parameter {} of {} is {:?}, got {:?}",
+                    param, name, param_type, value
+                )));
+            }
+            if let ParamValue::Text(s) = value {
+                if s.contains([',', '{', '}', '\n', '\r']) {
+                    return Err(Error::CustomCommand(format!(
+                        "Invalid text for parameter {} of {}: {}",
+                        param, name, s
+                    )));
+                }
+            }
+        }
+        Ok(Command::Custom {
+            name: name.to_string(),
+            params: custom
+                .params
+                .into_iter()
+                .map(|(p, _)| p)
+                .zip(values)
+                .collect(),
+        })
+    }
+
+    /// Fails if a custom command does not match its registration, like a command built without
+    /// `Command::custom`, because it could not be read back from a file.
+    pub fn check(&self) -> Result<()> {
+        match self {
+            Command::Custom { name, params } => {
+                let values = params.iter().map(|(_, v)| v.clone()).collect();
+                if Command::custom(name, values)? != *self {
+                    return Err(Error::CustomCommand(format!(
+                        "Parameters of {} don't match its registration: {:?}",
+                        name, params
+                    )));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Command::Turn { .. } => "Turn",
+            Command::TurnRadius { .. } => "TurnRadius",
+            Command::DriveDist { .. } => "DriveDist",
+            Command::DriveLine { .. } => "DriveLine",
+            Command::AlignDist { .. } => "AlignDist",
+            Command::AlignLine { .. } => "AlignLine",
+            Command::TurnOneWheel { .. } => "TurnOneWheel",
+            Command::Custom { name, .. } => name,
+        }
+    }
+
+    /// Returns the names and values of the parameters.
+    pub fn params(&self) -> Vec<(&str, String)> {
+        match *self {
+            Command::Turn { angle } => vec![("angle", angle.to_string())],
+            Command::TurnRadius { radius, angle } => {
+                vec![("radius", radius.to_string()), ("angle", angle.to_string())]
+            }
+            Command::DriveDist { dist }
+            | Command::DriveLine { dist }
+            | Command::AlignDist { dist }
+            | Command::AlignLine { dist } => vec![("dist", dist.to_string())],
+            Command::TurnOneWheel { angle, direction } => vec![
+                ("angle", angle.to_string()),
+                ("direction", direction.to_string()),
+            ],
+            Command::Custom { ref params, .. } => params
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Writes the command with its named parameters like "TurnRadius { radius: 10, angle: 90 }",
+    /// the text can be parsed back with `str::parse`.
+    pub fn write(&self) -> String {
+        let params = self
+            .params()
+            .into_iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<String>>();
+        if params.is_empty() {
+            return format!("{} {{}}", self.name());
         }
+        format!("{} {{ {} }}", self.name(), params.join(", "))
     }
 }
 
+impl FromStr for Command {
+    type Err = Error;
+
+    /// Parses the text written by `Command::write`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Parse(format!("Invalid command: {}", s));
+        let (name, params) = match s.strip_suffix(" {}") {
+            Some(name) => (name, vec![]),
+            None => {
+                let (name, params) = s
+                    .strip_suffix(" }")
+                    .and_then(|s| s.split_once(" { "))
+                    .ok_or_else(invalid)?;
+                let params = params
+                    .split(", ")
+                    .map(|p| p.split_once(": ").ok_or_else(invalid))
+                    .collect::<Result<Vec<(&str, &str)>>>()?;
+                (name, params)
+            }
+        };
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| *v)
+                .ok_or_else(|| Error::Parse(format!("Missing parameter {} in: {}", name, s)))
+        };
+        let number = |name: &str| {
+            param(name)?
+                .parse::<i16>()
+                .map_err(|e| Error::Parse(format!("Invalid parameter {} in {}: {}", name, s, e)))
+        };
+        Ok(match name {
+            "Turn" => Command::Turn {
+                angle: number("angle")?,
+            },
+            "TurnRadius" => Command::TurnRadius {
+                radius: number("radius")?,
+                angle: number("angle")?,
+            },
+            "DriveDist" => Command::DriveDist {
+                dist: number("dist")?,
+            },
+            "DriveLine" => Command::DriveLine {
+                dist: number("dist")?,
+            },
+            "AlignDist" => Command::AlignDist {
+                dist: number("dist")?,
+            },
+            "AlignLine" => Command::AlignLine {
+                dist: number("dist")?,
+            },
+            "TurnOneWheel" => Command::TurnOneWheel {
+                angle: number("angle")?,
+                direction: param("direction")?.parse()?,
+            },
+            _ => {
+                let custom = custom_command(name)
+                    .ok_or_else(|| Error::Parse(format!("Unknown command: {}", name)))?;
+                let values = custom
+                    .params
+                    .iter()
+                    .map(|(p, t)| ParamValue::parse(param(p)?, *t, p))
+                    .collect::<Result<Vec<ParamValue>>>()?;
+                Command::custom(name, values)?
+            }
+        })
+    }
+}
+
+/// How important an annotation is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl FromStr for Severity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "info" => Ok(Severity::Info),
+            "warning" => Ok(Severity::Warning),
+            "error" => Ok(Severity::Error),
+            _ => Err(Error::Parse(format!("Unknown severity: {}", s))),
+        }
+    }
+}
+
+/// A note about the recording, like "battery low" or "pushed the robot", kept apart from the
+/// commands so they can be shown on their own track.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub text: String,
+    pub severity: Option<Severity>,
+    /// A single word to group annotations, like "battery"
+    pub tag: Option<String>,
+}
+
+impl Annotation {
+    /// Creates an annotation without severity and tag.
+    pub fn new(text: impl ToString) -> Self {
+        Self {
+            text: text.to_string(),
+            severity: None,
+            tag: None,
+        }
+    }
+
+    /// Fails if the text contains a line break or the tag is empty, "-" or contains whitespace,
+    /// ',' or ')', because the annotation could not be read back from a file.
+    pub fn check(&self) -> Result<()> {
+        let invalid_tag = |tag: &String| {
+            tag.is_empty()
+                || tag == "-"
+                || tag.contains(|c: char| c.is_whitespace() || c == ',' || c == ')')
+        };
+        if self.text.contains(['\n', '\r']) || self.tag.as_ref().is_some_and(invalid_tag) {
+            return Err(Error::Parse(format!("Invalid annotation: {:?}", self)));
+        }
+        Ok(())
+    }
+
+    /// Writes the annotation like "(warning, battery) text", a missing severity or tag is
+    /// written as "-". The text can be parsed back with `str::parse`.
+    pub fn write(&self) -> String {
+        format!(
+            "({}, {}) {}",
+            self.severity.map_or("-".to_string(), |s| s.to_string()),
+            self.tag.as_deref().unwrap_or("-"),
+            self.text
+        )
+    }
+}
+
+impl FromStr for Annotation {
+    type Err = Error;
+
+    /// Parses the text written by `Annotation::write`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (severity, rest) = s
+            .strip_prefix('(')
+            .and_then(|s| s.split_once(", "))
+            .ok_or_else(|| Error::Parse(format!("Invalid annotation: {}", s)))?;
+        let (tag, text) = rest
+            .split_once(") ")
+            .or_else(|| rest.strip_suffix(')').map(|tag| (tag, "")))
+            .ok_or_else(|| Error::Parse(format!("Invalid annotation: {}", s)))?;
+        Ok(Self {
+            text: text.to_string(),
+            severity: match severity {
+                "-" => None,
+                s => Some(s.parse()?),
+            },
+            tag: (tag != "-").then(|| tag.to_string()),
+        })
+    }
+}
+
+/// How a command that was started with `begin_command` ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "snake_case")]
+pub enum Outcome {
+    Completed,
+    Aborted,
+    TimedOut,
+}
+
+impl FromStr for Outcome {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "completed" => Ok(Outcome::Completed),
+            "aborted" => Ok(Outcome::Aborted),
+            "timed_out" => Ok(Outcome::TimedOut),
+            _ => Err(Error::Parse(format!("Unknown outcome: {}", s))),
+        }
+    }
+}
+
+/// A command that was started with `begin_command` and has not ended yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenSpan {
+    /// The time the command was added at
+    pub start: u128,
+    pub command: Command,
+}
+
+/// The end of a command that was started with `begin_command`, it belongs to the
+/// `Data::Command` with the same start time and command.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandEnd {
+    pub start: u128,
+    pub command: Command,
+    pub outcome: Outcome,
+    /// The error the command ended with, if any
+    pub error: Option<String>,
+}
+
+impl CommandEnd {
+    /// Writes the end like "[start] timed_out DriveDist { dist: 500 }: motor stalled",
+    /// the text can be parsed back with `str::parse`.
+    pub fn write(&self) -> String {
+        let mut s = format!("[{}] {} {}", self.start, self.outcome, self.command.write());
+        if let Some(error) = &self.error {
+            s += &format!(": {}", error);
+        }
+        s
+    }
+}
+
+impl FromStr for CommandEnd {
+    type Err = Error;
+
+    /// Parses the text written by `CommandEnd::write`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Parse(format!("Invalid command end: {}", s));
+        let (start, rest) = s
+            .strip_prefix('[')
+            .and_then(|s| s.split_once("] "))
+            .ok_or_else(invalid)?;
+        let (outcome, rest) = rest.split_once(' ').ok_or_else(invalid)?;
+        // the parameters of a command never contain '}', so the command ends at the first one
+        let (command, error) = rest.split_at(rest.find('}').ok_or_else(invalid)? + 1);
+        Ok(Self {
+            start: start.parse().map_err(|_| invalid())?,
+            command: command.parse()?,
+            outcome: outcome.parse()?,
+            error: match error {
+                "" => None,
+                e => Some(e.strip_prefix(": ").ok_or_else(invalid)?.to_string()),
+            },
+        })
+    }
+}
+
+/// A command with the time it began and, if it was started with `begin_command` and has
+/// ended, the time and way it ended, see `RecData::command_spans`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSpan {
+    pub start: u128,
+    pub command: Command,
+    pub end: Option<u128>,
+    pub outcome: Option<Outcome>,
+    pub error: Option<String>,
+}
+
 // Enum representing different types of data.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Display)]
 pub enum Data {
-    Command(String),
+    /// A command and the time it was added at
+    Command(u128, Command),
+    /// The end of a command started with `begin_command` and the time it ended at
+    CommandEnd(u128, CommandEnd),
+    /// An annotation and the time it was added at
+    Annotation(u128, Annotation),
     RecordData(u128, Vec<DataType>),
     RecordDataOption(u128, Vec<Option<DataType>>),
 }
 
+/// Unit of the timestamps of a recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Millis,
+    Micros,
+}
+
+impl TimeUnit {
+    /// Returns the time since `start` in this unit.
+    pub fn since(self, start: Instant) -> u128 {
+        match self {
+            TimeUnit::Millis => start.elapsed().as_millis(),
+            TimeUnit::Micros => start.elapsed().as_micros(),
+        }
+    }
+
+    /// Returns the duration of `time` in this unit.
+    pub fn duration(self, time: u128) -> Duration {
+        let time = u64::try_from(time).unwrap_or(u64::MAX);
+        match self {
+            TimeUnit::Millis => Duration::from_millis(time),
+            TimeUnit::Micros => Duration::from_micros(time),
+        }
+    }
+}
+
+impl Display for TimeUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeUnit::Millis => write!(f, "ms"),
+            TimeUnit::Micros => write!(f, "us"),
+        }
+    }
+}
+
+impl FromStr for TimeUnit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ms" => Ok(TimeUnit::Millis),
+            "us" => Ok(TimeUnit::Micros),
+            _ => Err(Error::Parse(format!("Unknown time unit: {}", s))),
+        }
+    }
+}
+
+/// When the clock of a recording starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartMode {
+    /// The first record, command or comment starts the session, unless `start_session` was called
+    FirstEntry,
+    /// Only `start_session` starts the session, everything added before is ignored
+    Explicit,
+}
+
+/// Metadata key of the time unit, files without it were recorded in milliseconds.
+pub(crate) const TIME_UNIT_KEY: &str = "time_unit";
+/// Metadata key of the wall-clock start time in milliseconds since the unix epoch.
+pub(crate) const START_TIME_KEY: &str = "start_time";
+/// Metadata key of the id of the recording, it changes whenever the timestamps restart at 0.
+pub(crate) const RECORDING_ID_KEY: &str = "recording_id";
+/// Metadata key of the definitions of the custom channels, see `register_custom_channels`.
+pub(crate) const CUSTOM_CHANNELS_KEY: &str = "custom_channels";
+
+/// Registers the custom channels of a header, so its data can be decoded and parsed.
+pub(crate) fn register_header_channels(metadata: &[(String, String)]) -> Result<()> {
+    for (_, value) in metadata
+        .iter()
+        .filter(|(key, _)| key == CUSTOM_CHANNELS_KEY)
+    {
+        let channels = value
+            .split("; ")
+            .map(|c| c.parse())
+            .collect::<Result<Vec<CustomChannel>>>()?;
+        register_custom_channels(&channels)?;
+    }
+    Ok(())
+}
+
 // Struct representing recorded data.
 #[derive(Debug, Clone)]
 pub struct RecData {
     /// first the format of the current data
     pub data: Vec<Data>,
+    /// key/value pairs describing the recording, see `set_metadata`
+    pub metadata: Vec<(String, String)>,
     commands: Vec<Command>,
+    /// monotonic start of the session, the timestamps are relative to it
+    start: Option<Instant>,
+    /// set by `end_session`, nothing is recorded until the next session starts
+    ended: bool,
+    /// time at `end_session`, the next session continues from it while the data is kept
+    end_time: u128,
+    start_mode: StartMode,
+    /// wall-clock start in milliseconds since the unix epoch, 0 if the recording has not started
     start_time: u128,
+    /// changes whenever the timestamps restart at 0, 0 if the recording has not started
+    recording_id: u64,
+    time_unit: TimeUnit,
     right_total_d: f32,
     left_total_d: f32,
     first_time: bool,
@@ -85,17 +707,129 @@ impl RecData {
     const fn new() -> Self {
         Self {
             data: vec![],
+            metadata: vec![],
             commands: vec![],
+            start: None,
+            ended: false,
+            end_time: 0,
+            start_mode: StartMode::FirstEntry,
             start_time: 0,
+            recording_id: 0,
+            time_unit: TimeUnit::Micros,
             right_total_d: 0.0,
             left_total_d: 0.0,
             first_time: true,
         }
     }
+
+    /// Creates the recording read from a file with the metadata of its header.
+    pub(crate) fn from_file(data: Vec<Data>, metadata: Vec<(String, String)>) -> Result<Self> {
+        let mut rec_data = Self {
+            data,
+            // files without a time unit were written before timestamps had microseconds
+            time_unit: TimeUnit::Millis,
+            ..Self::new()
+        };
+        rec_data.set_header_metadata(metadata)?;
+        Ok(rec_data)
+    }
+
+    pub fn time_unit(&self) -> TimeUnit {
+        self.time_unit
+    }
+
+    /// Returns every recorded command with its end, if it was started with `begin_command`
+    /// and the end was recorded.
+    pub fn command_spans(&self) -> Vec<CommandSpan> {
+        let mut spans = vec![];
+        for d in &self.data {
+            match d {
+                Data::Command(t, command) => spans.push(CommandSpan {
+                    start: *t,
+                    command: command.clone(),
+                    end: None,
+                    outcome: None,
+                    error: None,
+                }),
+                Data::CommandEnd(t, end) => {
+                    if let Some(span) = spans.iter_mut().rev().find(|s| {
+                        s.end.is_none() && s.start == end.start && s.command == end.command
+                    }) {
+                        span.end = Some(*t);
+                        span.outcome = Some(end.outcome);
+                        span.error = end.error.clone();
+                    }
+                }
+                _ => {}
+            }
+        }
+        spans
+    }
+
+    /// Returns the wall-clock start in milliseconds since the unix epoch, 0 if unknown.
+    pub fn start_time(&self) -> u128 {
+        self.start_time
+    }
+
+    /// Returns the id of the recording, 0 if it has not started.
+    /// Timestamps are only comparable between entries with the same id.
+    pub fn recording_id(&self) -> u64 {
+        self.recording_id
+    }
+
+    /// Returns the metadata as it is written to the headers of files and sent to the clients:
+    /// the time unit, the wall-clock start time, the recording id and the registered custom
+    /// channels, followed by the metadata of the user.
+    pub fn header_metadata(&self) -> Vec<(String, String)> {
+        let mut metadata = vec![(TIME_UNIT_KEY.to_string(), self.time_unit.to_string())];
+        if self.start_time != 0 {
+            metadata.push((START_TIME_KEY.to_string(), self.start_time.to_string()));
+        }
+        if self.recording_id != 0 {
+            metadata.push((RECORDING_ID_KEY.to_string(), self.recording_id.to_string()));
+        }
+        let channels = custom_channels();
+        if !channels.is_empty() {
+            let channels = channels
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>();
+            metadata.push((CUSTOM_CHANNELS_KEY.to_string(), channels.join("; ")));
+        }
+        metadata.extend(self.metadata.iter().cloned());
+        metadata
+    }
+
+    /// Sets the metadata read from a header, see `header_metadata`.
+    /// The custom channels are registered instead of stored.
+    pub(crate) fn set_header_metadata(&mut self, metadata: Vec<(String, String)>) -> Result<()> {
+        register_header_channels(&metadata)?;
+        for (key, value) in metadata {
+            match key.as_str() {
+                TIME_UNIT_KEY => self.time_unit = value.parse()?,
+                START_TIME_KEY => {
+                    self.start_time = value
+                        .parse()
+                        .map_err(|_| Error::Parse(format!("Invalid start time: {}", value)))?
+                }
+                CUSTOM_CHANNELS_KEY => {}
+                RECORDING_ID_KEY => {
+                    self.recording_id = value
+                        .parse()
+                        .map_err(|_| Error::Parse(format!("Invalid recording id: {}", value)))?
+                }
+                _ => match self.metadata.iter_mut().find(|(k, _)| *k == key) {
+                    Some((_, v)) => *v = value,
+                    None => self.metadata.push((key, value)),
+                },
+            }
+        }
+        Ok(())
+    }
 }
 
-// Mutex for RecData and AtomicBool for server.
-static REC_DATA: Mutex<RecData> = Mutex::new(RecData::new());
+// Default global recorder and AtomicBool for server.
+static REC_DATA: Recorder = Recorder::streaming();
 static SERVER: AtomicBool = AtomicBool::new(false);
 
 // Function to set the server status.
@@ -103,254 +837,172 @@ pub fn set_server(b: bool) {
     SERVER.store(b, SeqCst);
 }
 
-// Macro to lock the mutex.
-macro_rules! lm {
-    ($mutex:expr) => {
-        $mutex.lock().expect("Mutex poisoned")
-    };
+/// Returns the default global recorder used by the free functions below.
+pub fn global_recorder() -> &'static Recorder {
+    &REC_DATA
+}
+
+pub fn start_recording() {
+    REC_DATA.start_recording()
+}
+
+pub fn stop_recording() {
+    REC_DATA.stop_recording()
+}
+
+pub fn is_recording() -> bool {
+    REC_DATA.is_recording()
+}
+
+pub fn set_writer(writer: Option<writer::CsvWriter>) -> Result<Option<writer::CsvWriter>> {
+    REC_DATA.set_writer(writer)
 }
 
-// Various functions to get and manipulate RecData.
+// Various functions to get and manipulate the data of the global recorder.
 pub fn get_rec_data() -> RecData {
-    lm!(REC_DATA).clone()
+    REC_DATA.get_rec_data()
 }
 
 pub fn get_rec_len() -> usize {
-    lm!(REC_DATA).data.len()
+    REC_DATA.get_rec_len()
 }
 
 pub fn get_rec_index(index: usize) -> Data {
-    lm!(REC_DATA).data[index].clone()
+    REC_DATA.get_rec_index(index)
+}
+
+pub fn try_get_rec_index(index: usize) -> Result<Data> {
+    REC_DATA.try_get_rec_index(index)
 }
 
 pub fn get_rec_start_time() -> u128 {
-    lm!(REC_DATA).start_time
+    REC_DATA.get_rec_start_time()
 }
 
 pub fn add_command(command: Command) {
-    lm!(REC_DATA).commands.push(command);
-    // todo: show also the params
-    lm!(REC_DATA).data.push(Data::Command(command.to_string()));
+    REC_DATA.add_command(command)
+}
+
+pub fn begin_command(command: Command) -> Option<OpenSpan> {
+    REC_DATA.begin_command(command)
+}
+
+pub fn try_begin_command(command: Command) -> Result<Option<OpenSpan>> {
+    REC_DATA.try_begin_command(command)
+}
+
+pub fn end_command(span: OpenSpan, outcome: Outcome, error: Option<String>) {
+    REC_DATA.end_command(span, outcome, error)
+}
+
+pub fn try_end_command(span: OpenSpan, outcome: Outcome, error: Option<String>) -> Result<()> {
+    REC_DATA.try_end_command(span, outcome, error)
 }
 
 pub fn add_comment(comment: String) {
-    lm!(REC_DATA).data.push(Data::Command(comment));
+    REC_DATA.add_comment(comment)
+}
+
+pub fn add_annotation(annotation: Annotation) {
+    REC_DATA.add_annotation(annotation)
+}
+
+pub fn try_add_annotation(annotation: Annotation) -> Result<()> {
+    REC_DATA.try_add_annotation(annotation)
 }
 
 pub fn get_data_name() -> String {
-    println!("COMMANDS: {:?}", lm!(REC_DATA).commands);
-    "data_".to_string()
-        + &*lm!(REC_DATA)
-            .commands
-            .iter()
-            .map(|c| c.to_string())
-            .collect::<Vec<String>>()
-            .join("_")
-            .replace(' ', "")
-            .to_string()
+    REC_DATA.get_data_name()
 }
 
 pub fn get_time() -> Option<u128> {
-    if lm!(REC_DATA).start_time == 0 {
-        None
-    } else {
-        Some(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                - lm!(REC_DATA).start_time,
-        )
-    }
+    REC_DATA.get_time()
 }
 
-pub fn save_data(mut data: Vec<DataType>) {
-    if lm!(REC_DATA).start_time == 0 {
-        lm!(REC_DATA).start_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-    }
-    // check if the data contains the same DataType multiple times
-    let mut had = vec![];
-    for d in &data {
-        if had.contains(d) {
-            panic!("data contains the same DataType multiple times");
-        }
-        had.push(*d);
-    }
-    // cache the total distance, because i cant look the mutex twice
-    let right_total_d = lm!(REC_DATA).right_total_d;
-    let left_total_d = lm!(REC_DATA).left_total_d;
-    // add RIGHT_TOTAL_D and LEFT_TOTAL_D to the DataType::DrivenDistance element
-    for d in data.iter_mut() {
-        if let DataType::DrivenDistance(r, l) = d {
-            *d = DataType::DrivenDistance(right_total_d + *r, left_total_d + *l);
-        }
-    }
-    let start_time = lm!(REC_DATA).start_time;
-    let rec_data = RecordData(
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            - start_time,
-        data.clone(),
-    );
-    if SERVER.load(SeqCst) {
-        add_data(rec_data.clone());
-    }
-    lm!(REC_DATA).data.push(rec_data);
+pub fn save_data(data: Vec<DataType>) {
+    REC_DATA.save_data(data)
+}
+
+pub fn try_save_data(data: Vec<DataType>) -> Result<()> {
+    REC_DATA.try_save_data(data)
 }
 
 pub fn save_record_data(data: Data) {
-    lm!(REC_DATA).data.push(data);
+    REC_DATA.save_record_data(data)
 }
 
 pub fn update_total_distance(right: f32, left: f32) {
-    if !lm!(REC_DATA).first_time {
-        lm!(REC_DATA).right_total_d += right;
-        lm!(REC_DATA).left_total_d += left;
-    } else {
-        lm!(REC_DATA).first_time = false;
-    }
+    REC_DATA.update_total_distance(right, left)
 }
 
 pub fn get_right_total_distance() -> f32 {
-    lm!(REC_DATA).right_total_d
+    REC_DATA.get_right_total_distance()
 }
 
 pub fn get_left_total_distance() -> f32 {
-    lm!(REC_DATA).left_total_d
+    REC_DATA.get_left_total_distance()
 }
 
 pub fn write_data(file_name: String) {
-    get_data_name();
-    println!(
-        "Writing data to {}: len: {}",
-        file_name,
-        lm!(REC_DATA).data.len()
-    );
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(file_name)
-        .unwrap();
-    file.set_len(0).unwrap();
-    // println!("Data: {:?}", DATA);
-    let mut data = vec![];
-    let mut used = vec![];
-    for dat in &*lm!(REC_DATA).data {
-        match dat {
-            RecordData(t, d) => {
-                // todo: maybe index compression
-                let mut temp = vec![];
-                for i in d {
-                    // convert to u8
-                    let index = i.to_u8();
-                    while temp.len() <= index as usize {
-                        temp.push(None);
-                    }
-                    if !used.contains(&index) {
-                        used.push(index);
-                    }
-                    temp[index as usize] = Some(*i);
-                }
-                data.push(RecordDataOption(*t, temp));
-            }
-            Data::Command(s) => {
-                data.push(Data::Command(s.clone()));
-            }
-            _ => {
-                panic!("Data is not RecordData or Command");
-            }
-        }
-    }
-    used.sort();
-    file.write_all(
-        format!(
-            "time, {}\n",
-            used.iter()
-                .map(|i| DataType::from_repr(*i)
-                    .expect("DataType not found")
-                    .write_description())
-                .filter(|x| x != &"".to_string())
-                .collect::<Vec<String>>()
-                .join(", ")
-        )
-        .as_bytes(),
-    )
-    .unwrap();
-    file.write_all(b"# Phoenix data\n").unwrap();
-    // todo: write time and date in this format: hh:mm:ss dd.mm.yyyy
-    // Get current time, date and the name of the current user and format it in a nice way
-    let now = chrono::Local::now();
-    let user = whoami::username();
-    let machine_name = fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
-    file.write_all(format!(
-        "# Created on {} at {} by {} on {}\n",
-        now.format("%d-%m-%Y"),
-        now.format("%H:%M:%S"),
-        user,
-        machine_name
-    ).as_bytes()).unwrap();
-    file.write_all(format!("# {}\n", get_data_name()).as_bytes())
-        .unwrap();
-    // todo reimplement this
-    // file.write_all(
-    //     format!(
-    //         "# k_p_drive: {}, k_i_drive: {}, k_d_drive: {}\n",
-    //         KPDRIVE.load(SeqCst),
-    //         KIDRIVE.load(SeqCst),
-    //         KDDRIVE.load(SeqCst)
-    //     )
-    //     .as_bytes(),
-    // )
-    // .unwrap();
-    // println!("Data: {:?}", data);
-    for data in data {
-        match data {
-            RecordDataOption(t, d) => {
-                file.write_all(
-                    format!(
-                        "{}, {}\n",
-                        t,
-                        d.iter()
-                            .enumerate()
-                            .map(|(i, x)| {
-                                if let Some(x) = x {
-                                    x.write()
-                                } else if used.contains(&(i as u8)) {
-                                    DataType::None(i as u8).write()
-                                } else {
-                                    // dont print null, because its not needed and we want to save disc space
-                                    "".to_string()
-                                }
-                            })
-                            .filter(|x| x != &"".to_string())
-                            .collect::<Vec<String>>()
-                            .join(", ")
-                    )
-                    .as_bytes(),
-                )
-                .unwrap();
-            }
-            Data::Command(s) => {
-                file.write_all(format!("# {}\n", s).as_bytes()).unwrap();
-            }
-            _ => {
-                panic!("Data::RecordDataOption or Data::Command expected");
-            }
-        }
-    }
+    REC_DATA.write_data(file_name)
+}
+
+pub fn try_write_data(file_name: String) -> Result<()> {
+    REC_DATA.try_write_data(file_name)
+}
+
+pub fn set_metadata(key: &str, value: impl ToString) {
+    REC_DATA.set_metadata(key, value)
+}
+
+pub fn try_set_metadata(key: &str, value: impl ToString) -> Result<()> {
+    REC_DATA.try_set_metadata(key, value)
+}
+
+pub fn set_start_mode(mode: StartMode) {
+    REC_DATA.set_start_mode(mode)
+}
+
+pub fn start_session() {
+    REC_DATA.start_session()
+}
+
+pub fn try_start_session() -> Result<()> {
+    REC_DATA.try_start_session()
+}
+
+pub fn end_session() {
+    REC_DATA.end_session()
+}
+
+pub fn set_time_unit(unit: TimeUnit) {
+    REC_DATA.set_time_unit(unit)
+}
+
+pub fn try_set_time_unit(unit: TimeUnit) -> Result<()> {
+    REC_DATA.try_set_time_unit(unit)
+}
+
+pub fn header_metadata() -> Vec<(String, String)> {
+    REC_DATA.header_metadata()
+}
+
+pub fn get_metadata(key: &str) -> Option<String> {
+    REC_DATA.get_metadata(key)
+}
+
+pub fn write_binary(file_name: String) {
+    REC_DATA.write_binary(file_name)
+}
+
+pub fn try_write_binary(file_name: String) -> Result<()> {
+    REC_DATA.try_write_binary(file_name)
 }
 
 pub fn clear_data() {
-    *lm!(REC_DATA) = RecData::default();
+    REC_DATA.clear_data()
 }
 /// This Function deletes the first n entries from the data, but keeps the rest.
 pub fn delete_data(n: usize) {
-    if n < lm!(REC_DATA).data.len() {
-        lm!(REC_DATA).data.drain(0..n);
-    }
+    REC_DATA.delete_data(n)
 }
@@ -0,0 +1,415 @@
+//! The wire protocol between the server on the robot and the clients.
+//!
+//! Every message is sent as a frame:
+//!
+//! | bytes | content                                   |
+//! |-------|-------------------------------------------|
+//! | 1     | message type, see [`MessageType`]         |
+//! | 4     | length of the payload, u32 little endian, at most [`MAX_FRAME_LEN`] |
+//! | n     | payload                                   |
+//!
+//! The payload depends on the message type:
+//! - `Hello`: the protocol version as u16 little endian, then the data type id version as u8
+//! - `Data`: lz4 compressed bincode of a `Vec<Data>`, also at most `MAX_FRAME_LEN` once
+//!   decompressed
+//! - `Request`: bincode of a [`Request`]
+//! - `Heartbeat` and `Close`: empty
+//! - `Error`: an utf-8 message
+//! - `Dropped`: the amount of records dropped since the last `Dropped` as u64 little endian
+//! - `Reply`: bincode of a [`Reply`]
+//! - `Metadata`: bincode of the key/value pairs of the recording
+//! - `Annotation`: bincode of the time and the [`Annotation`](crate::Annotation)
+//!
+//! A connection starts with the client sending `Hello` with its [`PROTOCOL_VERSION`] and
+//! [`DATA_TYPE_ID_VERSION`](crate::data_types::DATA_TYPE_ID_VERSION). The server answers with its own `Hello`, or with an `Error`
+//! followed by `Close` if it can't speak the versions of the client. The client then sends [`Request::Subscribe`], after which
+//! the server streams `Data` and `Annotation`s in the order they were recorded, and the client can send further `Request`s, which the server answers
+//! in order with a `Reply` or an `Error`. The server sends the `Metadata` of the recording right
+//! after the subscription and again whenever it changes. If the server had to drop
+//! records because the client did not keep up, it sends `Dropped` before the next `Data`.
+//! Either side ends the connection by sending `Close`.
+//!
+//! Both sides send `Heartbeat` regularly while they have nothing else to send. A peer that sends
+//! nothing within its configured timeout is considered dead: the server frees its slot and the
+//! client reconnects.
+use crate::{Annotation, Data, Error, Result};
+use lz4_compression::prelude::{compress, decompress};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Version of the protocol, bump this whenever the format of a message changes.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Largest payload of a frame. A longer frame comes from a broken or hostile peer or a corrupt
+/// file and is rejected before its buffer is allocated.
+pub const MAX_FRAME_LEN: usize = 4 * 1024 * 1024;
+
+/// The first byte of every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    Hello = 1,
+    Data = 2,
+    Request = 3,
+    Heartbeat = 4,
+    Error = 5,
+    Close = 6,
+    Dropped = 7,
+    Reply = 8,
+    Metadata = 9,
+    Annotation = 10,
+}
+
+impl MessageType {
+    pub fn from_u8(b: u8) -> Option<MessageType> {
+        match b {
+            1 => Some(MessageType::Hello),
+            2 => Some(MessageType::Data),
+            3 => Some(MessageType::Request),
+            4 => Some(MessageType::Heartbeat),
+            5 => Some(MessageType::Error),
+            6 => Some(MessageType::Close),
+            7 => Some(MessageType::Dropped),
+            8 => Some(MessageType::Reply),
+            9 => Some(MessageType::Metadata),
+            10 => Some(MessageType::Annotation),
+            _ => None,
+        }
+    }
+}
+
+/// Requests a client can send to the server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Request {
+    /// Start streaming, if `replay_from` is given the recorded history from this timestamp onward
+    /// (0 for everything) is sent before the live data. A client that resumes sends the
+    /// `recording_id` of its data, if the recording changed since, the whole history is sent.
+    Subscribe {
+        replay_from: Option<u128>,
+        recording_id: Option<u64>,
+    },
+    /// Resume recording on the robot, see `start_recording`
+    StartRecording,
+    /// Pause recording on the robot, see `stop_recording`
+    StopRecording,
+    ClearData,
+    /// Delete the first n entries of the recording, see `delete_data`
+    DeleteData(usize),
+    /// Write the recording to this file in the `write_dir` of the server, see `write_data`.
+    /// Only a file name is accepted, no path
+    WriteData(String),
+    AddComment(String),
+    /// Ask for the amount of recorded entries, answered with `Reply::RecLen`
+    GetRecLen,
+}
+
+/// Answers of the server to a `Request` other than `Subscribe`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Reply {
+    /// The request was executed
+    Done,
+    RecLen(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// Handshake with the protocol version and the data type id version of the sender
+    Hello(u16, u8),
+    Data(Vec<Data>),
+    Request(Request),
+    Heartbeat,
+    Error(String),
+    Close,
+    /// The server dropped this many records since the last `Dropped`, so the stream has a gap
+    Dropped(u64),
+    Reply(Reply),
+    /// All key/value pairs describing the recording
+    Metadata(Vec<(String, String)>),
+    /// An annotation and the time it was added at, sent apart from the `Data`
+    Annotation(u128, Annotation),
+}
+
+impl Message {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Message::Hello(..) => MessageType::Hello,
+            Message::Data(_) => MessageType::Data,
+            Message::Request(_) => MessageType::Request,
+            Message::Heartbeat => MessageType::Heartbeat,
+            Message::Error(_) => MessageType::Error,
+            Message::Close => MessageType::Close,
+            Message::Dropped(_) => MessageType::Dropped,
+            Message::Reply(_) => MessageType::Reply,
+            Message::Metadata(_) => MessageType::Metadata,
+            Message::Annotation(..) => MessageType::Annotation,
+        }
+    }
+
+    fn payload(&self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Message::Hello(version, id_version) => {
+                let mut payload = version.to_le_bytes().to_vec();
+                payload.push(*id_version);
+                payload
+            }
+            Message::Data(data) => {
+                let data = bincode::serialize(data)?;
+                if data.len() > MAX_FRAME_LEN {
+                    return Err(Error::Protocol(format!(
+                        "Data of {} bytes is larger than the maximum of {} bytes",
+                        data.len(),
+                        MAX_FRAME_LEN
+                    )));
+                }
+                compress(&data)
+            }
+            Message::Request(request) => bincode::serialize(request)?,
+            Message::Heartbeat | Message::Close => vec![],
+            Message::Error(e) => e.as_bytes().to_vec(),
+            Message::Dropped(n) => n.to_le_bytes().to_vec(),
+            Message::Reply(reply) => bincode::serialize(reply)?,
+            Message::Metadata(metadata) => bincode::serialize(metadata)?,
+            Message::Annotation(time, annotation) => bincode::serialize(&(time, annotation))?,
+        })
+    }
+
+    /// Decodes the payload of a frame read with `read_frame`.
+    pub fn from_payload(ty: MessageType, payload: Vec<u8>) -> Result<Message> {
+        Ok(match ty {
+            MessageType::Hello => {
+                let version = payload
+                    .get(..3)
+                    .ok_or_else(|| Error::Protocol("Hello without versions".to_string()))?;
+                Message::Hello(u16::from_le_bytes([version[0], version[1]]), version[2])
+            }
+            MessageType::Data => {
+                // a small payload can expand a thousandfold, check before allocating
+                match decompressed_len(&payload) {
+                    Some(len) if len <= MAX_FRAME_LEN => {}
+                    Some(len) => {
+                        return Err(Error::Protocol(format!(
+                            "Data of {} bytes is larger than the maximum of {} bytes",
+                            len, MAX_FRAME_LEN
+                        )))
+                    }
+                    None => return Err(Error::Protocol("Truncated data".to_string())),
+                }
+                let data = decompress(&payload).map_err(|e| Error::Protocol(format!("{:?}", e)))?;
+                Message::Data(bincode::deserialize(&data)?)
+            }
+            MessageType::Request => Message::Request(bincode::deserialize(&payload)?),
+            MessageType::Heartbeat => Message::Heartbeat,
+            MessageType::Error => Message::Error(String::from_utf8_lossy(&payload).to_string()),
+            MessageType::Close => Message::Close,
+            MessageType::Dropped => {
+                let n = payload
+                    .get(..8)
+                    .ok_or_else(|| Error::Protocol("Dropped without count".to_string()))?;
+                Message::Dropped(u64::from_le_bytes(n.try_into().expect("slice has 8 bytes")))
+            }
+            MessageType::Reply => Message::Reply(bincode::deserialize(&payload)?),
+            MessageType::Metadata => Message::Metadata(bincode::deserialize(&payload)?),
+            MessageType::Annotation => {
+                let (time, annotation) = bincode::deserialize(&payload)?;
+                Message::Annotation(time, annotation)
+            }
+        })
+    }
+}
+
+/// Returns the length of the lz4 block once it is decompressed, without decompressing it, or
+/// `None` if the block ends in the middle of a sequence.
+///
+/// A block is a list of sequences: a token with the length of the literals in the high and the
+/// length of the match minus 4 in the low 4 bits, both extended by bytes while they are 255,
+/// the literals and a 2 byte offset of the match. The last sequence has no match.
+fn decompressed_len(mut block: &[u8]) -> Option<usize> {
+    fn extended(block: &mut &[u8], mut len: usize) -> Option<usize> {
+        if len == 15 {
+            loop {
+                let (&byte, rest) = block.split_first()?;
+                *block = rest;
+                len = len.checked_add(byte as usize)?;
+                if byte != 255 {
+                    break;
+                }
+            }
+        }
+        Some(len)
+    }
+    let mut len = 0usize;
+    while let Some((&token, rest)) = block.split_first() {
+        block = rest;
+        let literals = extended(&mut block, (token >> 4) as usize)?;
+        block = block.get(literals..)?;
+        len = len.checked_add(literals)?;
+        if block.is_empty() {
+            break;
+        }
+        block = block.get(2..)?;
+        len = len.checked_add(extended(&mut block, (token & 0xF) as usize)? + 4)?;
+    }
+    Some(len)
+}
+
+/// Writes the message as one frame.
+pub fn write_message(writer: &mut impl Write, message: &Message) -> Result<()> {
+    let payload = message.payload()?;
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(Error::Protocol(format!(
+            "{:?} of {} bytes is larger than the maximum of {} bytes",
+            message.message_type(),
+            payload.len(),
+            MAX_FRAME_LEN
+        )));
+    }
+    let mut frame = Vec::with_capacity(payload.len() + 5);
+    frame.push(message.message_type() as u8);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)?;
+    Ok(())
+}
+
+/// Reads the rest of a frame whose message type byte has already been read.
+pub fn read_message_body(reader: &mut impl Read, ty: u8) -> Result<Message> {
+    let (ty, payload) = read_frame_body(reader, ty)?;
+    Message::from_payload(ty, payload)
+}
+
+/// Reads one frame without decoding its payload.
+///
+/// After an error here the position in the stream is unknown. If only `Message::from_payload`
+/// fails, the frame was read completely and the next frame can still be read.
+pub fn read_frame(reader: &mut impl Read) -> Result<(MessageType, Vec<u8>)> {
+    let mut ty = [0u8; 1];
+    reader.read_exact(&mut ty)?;
+    read_frame_body(reader, ty[0])
+}
+
+fn read_frame_body(reader: &mut impl Read, ty: u8) -> Result<(MessageType, Vec<u8>)> {
+    let ty = MessageType::from_u8(ty)
+        .ok_or_else(|| Error::Protocol(format!("Unknown message type: {}", ty)))?;
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(Error::Protocol(format!(
+            "Frame of {} bytes is larger than the maximum of {} bytes",
+            len, MAX_FRAME_LEN
+        )));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok((ty, payload))
+}
+
+/// Reads one frame.
+pub fn read_message(reader: &mut impl Read) -> Result<Message> {
+    let mut ty = [0u8; 1];
+    reader.read_exact(&mut ty)?;
+    read_message_body(reader, ty[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{DataType, DATA_TYPE_ID_VERSION};
+    use crate::{Annotation, Command, Severity};
+    use std::io::Cursor;
+
+    fn round_trip(message: Message) {
+        let mut buf = vec![];
+        write_message(&mut buf, &message).unwrap();
+        let read = read_message(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(read, message);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        round_trip(Message::Hello(PROTOCOL_VERSION, DATA_TYPE_ID_VERSION));
+        round_trip(Message::Data(vec![
+            Data::RecordData(1, vec![DataType::Distance(3)]),
+            Data::Command(
+                2,
+                Command::TurnRadius {
+                    radius: 10,
+                    angle: 90,
+                },
+            ),
+        ]));
+        round_trip(Message::Request(Request::Subscribe {
+            replay_from: Some(5),
+            recording_id: Some(7),
+        }));
+        round_trip(Message::Request(Request::WriteData("data.csv".to_string())));
+        round_trip(Message::Heartbeat);
+        round_trip(Message::Error("failed".to_string()));
+        round_trip(Message::Close);
+        round_trip(Message::Dropped(7));
+        round_trip(Message::Reply(Reply::RecLen(3)));
+        round_trip(Message::Metadata(vec![(
+            "key".to_string(),
+            "value".to_string(),
+        )]));
+        round_trip(Message::Annotation(
+            4,
+            Annotation {
+                severity: Some(Severity::Warning),
+                tag: Some("battery".to_string()),
+                ..Annotation::new("low")
+            },
+        ));
+    }
+
+    #[test]
+    fn frame_longer_than_max_is_rejected() {
+        let mut frame = vec![MessageType::Data as u8];
+        frame.extend_from_slice(&u32::MAX.to_le_bytes());
+        let result = read_message(&mut Cursor::new(frame));
+        assert!(matches!(result, Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn data_larger_than_max_once_decompressed_is_rejected() {
+        // one literal and a match that repeats it about a billion times, in less than 4 MiB
+        let mut block = vec![0x1F, 0, 1, 0];
+        block.extend(vec![255; MAX_FRAME_LEN - 16]);
+        block.push(0);
+        assert!(decompressed_len(&block).unwrap() > 1_000_000_000);
+        let mut frame = vec![MessageType::Data as u8];
+        frame.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&block);
+        let result = read_message(&mut Cursor::new(frame));
+        assert!(matches!(result, Err(Error::Protocol(_))));
+
+        let data = vec![Data::RecordData(1, vec![DataType::Distance(3)]); MAX_FRAME_LEN / 8];
+        let result = write_message(&mut vec![], &Message::Data(data));
+        assert!(matches!(result, Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn decompressed_len_matches_decompress() {
+        let data = (0..10_000u32)
+            .flat_map(|i| (i % 7 * i).to_le_bytes())
+            .collect::<Vec<u8>>();
+        let block = compress(&data);
+        assert_eq!(decompressed_len(&block), Some(data.len()));
+        assert_eq!(decompressed_len(&block[..block.len() - 1]), None);
+    }
+
+    #[test]
+    fn unknown_message_type_is_rejected() {
+        let frame = vec![200, 0, 0, 0, 0];
+        let result = read_message(&mut Cursor::new(frame));
+        assert!(matches!(result, Err(Error::Protocol(_))));
+    }
+
+    #[test]
+    fn truncated_frame_is_an_io_error() {
+        let mut buf = vec![];
+        write_message(&mut buf, &Message::Error("failed".to_string())).unwrap();
+        buf.pop();
+        let result = read_message(&mut Cursor::new(buf));
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+}
@@ -0,0 +1,293 @@
+use crate::binary::read_binary_file;
+use crate::data_types::DataType;
+use crate::writer::{ANNOTATION_PREFIX, COMMAND_END_PREFIX, COMMAND_PREFIX, METADATA_PREFIX};
+use crate::{register_header_channels, Annotation, Data, Error, RecData, Result};
+use std::fs;
+
+/// First comment line written by `write_data`, it is followed by the creation line and the data name.
+const PHOENIX_HEADER: &str = "# Phoenix data";
+
+/// Maps the column descriptions of the header line back to the data types.
+///
+/// `write_data` writes the descriptions sorted by the column of the data type, so we can walk over
+/// all known data types in order and check if their description follows at the current position.
+/// The custom channels of the file have to be registered before, see `register_header_channels`.
+/// Returns the column and the amount of fields of every data type in the file.
+fn parse_header(header: &str) -> Result<Vec<(u8, usize)>> {
+    let mut columns = header.split(", ");
+    if columns.next() != Some("time") {
+        return Err(Error::Parse(format!(
+            "Header does not start with time: {}",
+            header
+        )));
+    }
+    let columns = columns.filter(|c| !c.is_empty()).collect::<Vec<&str>>();
+    let mut layout = vec![];
+    let mut pos = 0;
+    for column in DataType::columns() {
+        if pos == columns.len() {
+            break;
+        }
+        let description = DataType::column_description(column).unwrap_or_default();
+        let fields = description.split(", ").collect::<Vec<&str>>();
+        if columns[pos..].starts_with(&fields) {
+            layout.push((column, fields.len()));
+            pos += fields.len();
+        }
+    }
+    if pos != columns.len() {
+        return Err(Error::Parse(format!(
+            "Unknown column in header: {}",
+            columns[pos..].join(", ")
+        )));
+    }
+    Ok(layout)
+}
+
+/// Parses one data row like "12, 30, 30, null" into a `Data::RecordData`.
+fn parse_row(row: &str, layout: &[(u8, usize)]) -> Result<Data> {
+    let mut cells = row.split(", ");
+    let time = cells
+        .next()
+        .and_then(|t| t.parse::<u128>().ok())
+        .ok_or_else(|| Error::Parse(format!("Row does not start with a time: {}", row)))?;
+    let cells = cells.collect::<Vec<&str>>();
+    // a record without data is written as "time, "
+    if cells == [""] {
+        return Ok(Data::RecordData(time, vec![]));
+    }
+    let mut data = vec![];
+    let mut pos = 0;
+    // rows only contain the columns up to the last data type that was recorded in them
+    for (column, len) in layout {
+        if pos >= cells.len() {
+            break;
+        }
+        let fields = cells
+            .get(pos..pos + len)
+            .ok_or_else(|| Error::Parse(format!("Row is missing fields: {}", row)))?;
+        pos += len;
+        if fields.iter().all(|f| *f == "null") {
+            continue;
+        }
+        data.push(DataType::try_from_fields(*column, fields)?);
+    }
+    if pos != cells.len() {
+        return Err(Error::Parse(format!("Row has too many fields: {}", row)));
+    }
+    Ok(Data::RecordData(time, data))
+}
+
+/// Parses a "# @key: value" line of the header.
+fn parse_metadata(line: &str) -> Result<(String, String)> {
+    line.strip_prefix(METADATA_PREFIX)
+        .and_then(|entry| entry.split_once(": "))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| Error::Parse(format!("Invalid metadata: {}", line)))
+}
+
+/// Splits a "[time] text" command, annotation or comment into its time and text.
+fn parse_comment(comment: &str) -> Option<(u128, &str)> {
+    let (time, text) = comment.strip_prefix('[')?.split_once("] ")?;
+    Some((time.parse().ok()?, text))
+}
+
+/// Parses the content of a file written by `write_data` back into a list of `Data`.
+///
+/// Data rows become `Data::RecordData` with every `null` data type left out, "#> " lines become
+/// `Data::Command`, "#< " lines `Data::CommandEnd`, "#! " lines `Data::Annotation` and the comment lines of older files
+/// annotations without severity and tag. The header comments (creation date, data name and metadata) and
+/// metadata lines after the header are skipped.
+pub fn parse_data(content: &str) -> Result<Vec<Data>> {
+    Ok(parse_rec_data(content)?.data)
+}
+
+/// Same as `parse_data`, but also returns the metadata, later lines override earlier ones.
+fn parse_rec_data(content: &str) -> Result<RecData> {
+    let mut lines = content.lines();
+    let header = lines.next().unwrap_or_default();
+    let mut lines = lines.peekable();
+    let mut metadata = vec![];
+    if lines.peek() == Some(&PHOENIX_HEADER) {
+        lines.nth(2);
+        while let Some(line) = lines.next_if(|l| l.starts_with(METADATA_PREFIX)) {
+            metadata.push(parse_metadata(line)?);
+        }
+    }
+    // the header line can only be mapped once the custom channels of the file are known
+    register_header_channels(&metadata)?;
+    let layout = parse_header(header)?;
+    let mut data = vec![];
+    // comments of older files have no time, they get the time of the record before them
+    let mut time = 0;
+    for line in lines {
+        if let Some(command) = line.strip_prefix(COMMAND_PREFIX) {
+            let (t, command) = parse_comment(command)
+                .ok_or_else(|| Error::Parse(format!("Invalid command line: {}", line)))?;
+            data.push(Data::Command(t, command.parse()?));
+        } else if let Some(end) = line.strip_prefix(COMMAND_END_PREFIX) {
+            let (t, end) = parse_comment(end)
+                .ok_or_else(|| Error::Parse(format!("Invalid command end line: {}", line)))?;
+            data.push(Data::CommandEnd(t, end.parse()?));
+        } else if let Some(annotation) = line.strip_prefix(ANNOTATION_PREFIX) {
+            let (t, annotation) = parse_comment(annotation)
+                .ok_or_else(|| Error::Parse(format!("Invalid annotation line: {}", line)))?;
+            data.push(Data::Annotation(t, annotation.parse()?));
+        } else if line.starts_with(METADATA_PREFIX) {
+            // metadata that changed while the file was streamed, see `CsvWriter::set_metadata`
+            metadata.push(parse_metadata(line)?);
+        } else if let Some(comment) = line.strip_prefix("# ") {
+            let (t, comment) = parse_comment(comment).unwrap_or((time, comment));
+            data.push(Data::Annotation(t, Annotation::new(comment)));
+        } else if !line.is_empty() {
+            let row = parse_row(line, &layout)?;
+            if let Data::RecordData(t, _) = row {
+                time = t;
+            }
+            data.push(row);
+        }
+    }
+    RecData::from_file(data, metadata)
+}
+
+/// Reads a file written by `write_binary` back into a `RecData`.
+/// If the file was cut off, everything up to its last complete chunk is returned.
+pub fn read_binary(file_name: String) -> Result<RecData> {
+    Ok(read_binary_file(&file_name)?.1)
+}
+
+/// Reads a file written by `write_data` back into a `RecData`.
+pub fn read_data(file_name: String) -> Result<RecData> {
+    parse_rec_data(&fs::read_to_string(file_name)?)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::data_types::{register_custom_channel, NumberType};
+    use crate::writer::write_csv;
+    use crate::{
+        register_custom_command, Annotation, Command, CommandEnd, Outcome, ParamType, ParamValue,
+        Severity, CUSTOM_CHANNELS_KEY,
+    };
+    use std::sync::OnceLock;
+
+    /// Returns a path in the temp directory that is unique for the test.
+    pub(crate) fn temp_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("phoenix_rec_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Leaves out the custom channels, they depend on what the other tests registered.
+    pub(crate) fn without_channels(metadata: Vec<(String, String)>) -> Vec<(String, String)> {
+        metadata
+            .into_iter()
+            .filter(|(key, _)| key != CUSTOM_CHANNELS_KEY)
+            .collect()
+    }
+
+    /// A recording with every kind of entry, nulls, a custom channel and a custom command, and
+    /// its metadata.
+    pub(crate) fn sample() -> (Vec<Data>, Vec<(String, String)>) {
+        static CHANNEL: OnceLock<u8> = OnceLock::new();
+        let channel = *CHANNEL.get_or_init(|| {
+            register_custom_command(
+                "Grab",
+                &[
+                    ("force", ParamType::Float),
+                    ("side", ParamType::Text),
+                    ("closed", ParamType::Bool),
+                    ("tries", ParamType::Int),
+                ],
+            )
+            .unwrap();
+            register_custom_channel("grabber", &["force", "angle"], NumberType::Float).unwrap()
+        });
+        let grab = Command::custom(
+            "Grab",
+            vec![
+                ParamValue::Float(2.5),
+                ParamValue::Text("left arm".to_string()),
+                ParamValue::Bool(true),
+                ParamValue::Int(-3),
+            ],
+        )
+        .unwrap();
+        let drive = Command::DriveDist { dist: 500 };
+        let data = vec![
+            Data::RecordData(
+                10,
+                vec![
+                    DataType::Color(1, 2),
+                    DataType::Distance(3),
+                    DataType::custom(channel, &[1.5, -2.0]).unwrap(),
+                ],
+            ),
+            Data::Command(15, drive.clone()),
+            // a record without data
+            Data::RecordData(17, vec![]),
+            // the columns before the distance are written as null
+            Data::RecordData(20, vec![DataType::Distance(4)]),
+            Data::Annotation(
+                25,
+                Annotation {
+                    severity: Some(Severity::Warning),
+                    tag: Some("battery".to_string()),
+                    ..Annotation::new("low: 6.1 V")
+                },
+            ),
+            Data::Annotation(26, Annotation::new("plain note")),
+            Data::CommandEnd(
+                30,
+                CommandEnd {
+                    start: 15,
+                    command: drive,
+                    outcome: Outcome::TimedOut,
+                    error: Some("motor stalled".to_string()),
+                },
+            ),
+            Data::Command(35, grab),
+            Data::RecordData(
+                40,
+                vec![
+                    DataType::DrivenDistance(1.25, -0.5),
+                    DataType::custom(channel, &[0.0, 90.0]).unwrap(),
+                ],
+            ),
+        ];
+        let metadata = vec![
+            ("time_unit".to_string(), "us".to_string()),
+            ("start_time".to_string(), "1700000000000".to_string()),
+            ("battery".to_string(), "7.9".to_string()),
+        ];
+        (data, metadata)
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let (data, metadata) = sample();
+        let file = temp_file("round_trip.csv");
+        write_csv(&file, &data, "data_test", &metadata).unwrap();
+        let rec_data = read_data(file.clone()).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(rec_data.data, data);
+        assert_eq!(without_channels(rec_data.header_metadata()), metadata);
+        assert_eq!(rec_data.start_time(), 1700000000000);
+    }
+
+    #[test]
+    fn legacy_comments_become_annotations() {
+        let content = "time, dist\n# Phoenix data\n# Created on 01-01-2024 at 10:00:00 by a on b\n# data_\n1, 3\n# Turn(5)\n";
+        let rec_data = parse_rec_data(content).unwrap();
+        assert_eq!(
+            rec_data.data,
+            vec![
+                Data::RecordData(1, vec![DataType::Distance(3)]),
+                Data::Annotation(1, Annotation::new("Turn(5)")),
+            ]
+        );
+        assert_eq!(rec_data.time_unit(), crate::TimeUnit::Millis);
+    }
+}
@@ -0,0 +1,674 @@
+use crate::binary::{BinaryWriter, DEFAULT_CHUNK_SIZE};
+use crate::data_types::{custom_channel, DataType};
+use crate::server::{add_data, send_metadata};
+use crate::writer::{write_csv, CsvWriter};
+use crate::Data::RecordData;
+use crate::{
+    Annotation, Command, CommandEnd, Data, Error, OpenSpan, Outcome, RecData, Result, StartMode,
+    TimeUnit, CUSTOM_CHANNELS_KEY, RECORDING_ID_KEY, SERVER, START_TIME_KEY, TIME_UNIT_KEY,
+};
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// The last recording id handed out, see `new_recording_id`.
+static LAST_RECORDING_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Returns a new recording id. It is based on the wall clock in nanoseconds, so ids of a
+/// restarted server don't repeat the ids of the previous run, and always increases within a run.
+fn new_recording_id() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64;
+    let last = LAST_RECORDING_ID
+        .fetch_update(SeqCst, SeqCst, |last| Some(now.max(last + 1)))
+        .unwrap();
+    now.max(last + 1)
+}
+
+/// A handle to a single recording.
+///
+/// Every `Recorder` owns its own [`RecData`], so one process can hold several independent
+/// recordings. The free functions in the crate root forward to a default global instance,
+/// see [`crate::global_recorder`].
+#[derive(Debug)]
+pub struct Recorder {
+    rec_data: Mutex<RecData>,
+    /// whether data saved to this recorder should also be queued for the server
+    stream: AtomicBool,
+    /// whether `save_data` records anything, see `stop_recording`
+    recording: AtomicBool,
+    /// file the data is also written to while recording, see `set_writer`
+    writer: Mutex<Option<CsvWriter>>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder {
+    pub const fn new() -> Self {
+        Self {
+            rec_data: Mutex::new(RecData::new()),
+            stream: AtomicBool::new(false),
+            recording: AtomicBool::new(true),
+            writer: Mutex::new(None),
+        }
+    }
+
+    /// Creates a recorder whose data is also queued for the server while it is running.
+    pub const fn streaming() -> Self {
+        Self {
+            rec_data: Mutex::new(RecData::new()),
+            stream: AtomicBool::new(true),
+            recording: AtomicBool::new(true),
+            writer: Mutex::new(None),
+        }
+    }
+
+    /// Sets whether data saved to this recorder should be sent to the server.
+    pub fn set_stream(&self, b: bool) {
+        self.stream.store(b, SeqCst);
+    }
+
+    /// Resumes recording after `stop_recording`, recording is on for a new recorder.
+    pub fn start_recording(&self) {
+        self.recording.store(true, SeqCst);
+    }
+
+    /// Pauses the recording, all records, commands and annotations are ignored until
+    /// `start_recording` is called.
+    pub fn stop_recording(&self) {
+        self.recording.store(false, SeqCst);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.load(SeqCst)
+    }
+
+    /// Sets the writer every new record, command and comment is appended to,
+    /// `None` stops writing. Returns the previous writer, which is flushed.
+    /// The writer gets the metadata of this recorder, see `CsvWriter::set_metadata`.
+    pub fn set_writer(&self, mut writer: Option<CsvWriter>) -> Result<Option<CsvWriter>> {
+        let rec_data = self.lock();
+        if let Some(writer) = writer.as_mut() {
+            writer.set_metadata(&rec_data.header_metadata())?;
+        }
+        let mut previous =
+            std::mem::replace(&mut *self.writer.lock().expect("Mutex poisoned"), writer);
+        drop(rec_data);
+        if let Some(previous) = previous.as_mut() {
+            previous.flush()?;
+        }
+        Ok(previous)
+    }
+
+    /// Runs `f` on the writer if there is one. Errors are only logged, the entry is already
+    /// recorded in memory and the robot must not stop because the file can't be written.
+    fn with_writer(&self, f: impl FnOnce(&mut CsvWriter) -> Result<()>) {
+        if let Some(writer) = self.writer.lock().expect("Mutex poisoned").as_mut() {
+            if let Err(e) = f(writer) {
+                println!("Failed to write to file: {}", e);
+            }
+        }
+    }
+
+    /// Sends the changed metadata to the clients and passes it to the writer.
+    fn metadata_changed(&self, rec_data: &RecData) {
+        if self.stream.load(SeqCst) && SERVER.load(SeqCst) {
+            send_metadata(rec_data.header_metadata());
+        }
+        self.with_writer(|w| w.set_metadata(&rec_data.header_metadata()));
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, RecData> {
+        self.rec_data.lock().expect("Mutex poisoned")
+    }
+
+    pub fn get_rec_data(&self) -> RecData {
+        self.lock().clone()
+    }
+
+    pub fn get_rec_len(&self) -> usize {
+        self.lock().data.len()
+    }
+
+    pub fn get_rec_index(&self, index: usize) -> Data {
+        self.try_get_rec_index(index).unwrap()
+    }
+
+    /// Same as `get_rec_index`, but returns an error if the index is out of range.
+    pub fn try_get_rec_index(&self, index: usize) -> Result<Data> {
+        self.lock()
+            .data
+            .get(index)
+            .cloned()
+            .ok_or(Error::IndexOutOfRange(index))
+    }
+
+    /// Returns the wall-clock start of the recording in milliseconds since the unix epoch,
+    /// 0 if nothing was recorded yet.
+    pub fn get_rec_start_time(&self) -> u128 {
+        self.lock().start_time
+    }
+
+    /// Adds the command with the current time, it is ignored outside of a session.
+    pub fn add_command(&self, command: Command) {
+        self.begin_command(command);
+    }
+
+    /// Same as `try_begin_command`, but only logs the error.
+    pub fn begin_command(&self, command: Command) -> Option<OpenSpan> {
+        self.try_begin_command(command).unwrap_or_else(|e| {
+            println!("Failed to add command: {}", e);
+            None
+        })
+    }
+
+    /// Adds the command like `add_command` and returns the span to end it with `end_command`,
+    /// `None` outside of a session.
+    /// Fails if the command could not be read back from a file, see `Command::check`.
+    pub fn try_begin_command(&self, command: Command) -> Result<Option<OpenSpan>> {
+        command.check()?;
+        let mut rec_data = self.lock();
+        let Some(time) = self.entry_time(&mut rec_data) else {
+            return Ok(None);
+        };
+        rec_data.commands.push(command.clone());
+        self.with_writer(|w| w.write_command(time, &command));
+        self.push_entry(&mut rec_data, Data::Command(time, command.clone()));
+        Ok(Some(OpenSpan {
+            start: time,
+            command,
+        }))
+    }
+
+    pub fn end_command(&self, span: OpenSpan, outcome: Outcome, error: Option<String>) {
+        if let Err(e) = self.try_end_command(span, outcome, error) {
+            println!("Failed to end command: {}", e);
+        }
+    }
+
+    /// Records the end of the command with the current time, how it ended and the error it
+    /// ended with. It is ignored outside of a session.
+    /// Fails if the error contains a line break.
+    pub fn try_end_command(
+        &self,
+        span: OpenSpan,
+        outcome: Outcome,
+        error: Option<String>,
+    ) -> Result<()> {
+        if let Some(e) = error.as_ref().filter(|e| e.contains(['\n', '\r'])) {
+            return Err(Error::Parse(format!("Invalid command error: {}", e)));
+        }
+        let mut rec_data = self.lock();
+        let Some(time) = self.entry_time(&mut rec_data) else {
+            return Ok(());
+        };
+        let end = CommandEnd {
+            start: span.start,
+            command: span.command,
+            outcome,
+            error,
+        };
+        self.with_writer(|w| w.write_command_end(time, &end));
+        self.push_entry(&mut rec_data, Data::CommandEnd(time, end));
+        Ok(())
+    }
+
+    /// Adds the comment as annotation without severity and tag, see `add_annotation`.
+    pub fn add_comment(&self, comment: String) {
+        if let Err(e) = self.try_add_annotation(Annotation::new(comment)) {
+            println!("Failed to add comment: {}", e);
+        }
+    }
+
+    pub fn add_annotation(&self, annotation: Annotation) {
+        if let Err(e) = self.try_add_annotation(annotation) {
+            println!("Failed to add annotation: {}", e);
+        }
+    }
+
+    /// Adds the annotation with the current time, it is ignored outside of a session.
+    /// Fails if the annotation could not be read back from a file, see `Annotation::check`.
+    pub fn try_add_annotation(&self, annotation: Annotation) -> Result<()> {
+        annotation.check()?;
+        let mut rec_data = self.lock();
+        let Some(time) = self.entry_time(&mut rec_data) else {
+            return Ok(());
+        };
+        self.with_writer(|w| w.write_annotation(time, &annotation));
+        self.push_entry(&mut rec_data, Data::Annotation(time, annotation));
+        Ok(())
+    }
+
+    fn push_entry(&self, rec_data: &mut RecData, data: Data) {
+        if self.stream.load(SeqCst) && SERVER.load(SeqCst) {
+            add_data(data.clone());
+        }
+        rec_data.data.push(data);
+    }
+
+    /// Sets when the session starts, see `StartMode`.
+    pub fn set_start_mode(&self, mode: StartMode) {
+        self.lock().start_mode = mode;
+    }
+
+    /// Same as `try_start_session`, but only logs the error.
+    pub fn start_session(&self) {
+        if let Err(e) = self.try_start_session() {
+            println!("{}", e);
+        }
+    }
+
+    /// Starts a session, the timestamps of all following entries are relative to now.
+    /// Fails if the session has already started.
+    ///
+    /// A new session after `end_session` continues with the time the previous session ended at,
+    /// so the timestamps of a recording never go back. It only starts at 0 again if the data
+    /// of the previous session was cleared.
+    pub fn try_start_session(&self) -> Result<()> {
+        let mut rec_data = self.lock();
+        if rec_data.start.is_some() && !rec_data.ended {
+            return Err(Error::Session("Session already started".to_string()));
+        }
+        self.start(&mut rec_data);
+        Ok(())
+    }
+
+    /// Ends the session, nothing is recorded until the next `start_session`.
+    pub fn end_session(&self) {
+        let mut rec_data = self.lock();
+        if let (Some(start), false) = (rec_data.start, rec_data.ended) {
+            rec_data.end_time = rec_data.time_unit.since(start);
+        }
+        rec_data.ended = true;
+    }
+
+    fn start(&self, rec_data: &mut RecData) -> Instant {
+        // the timestamps are monotonic, the wall-clock start is only kept for the header
+        let now = Instant::now();
+        let start = if rec_data.ended && !rec_data.data.is_empty() {
+            // the data of the previous session is kept, its timestamps must stay in order
+            now.checked_sub(rec_data.time_unit.duration(rec_data.end_time))
+                .unwrap_or(now)
+        } else {
+            rec_data.start_time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis();
+            // the timestamps restart at 0, clients must not resume with the ones they received
+            rec_data.recording_id = new_recording_id();
+            now
+        };
+        rec_data.start = Some(start);
+        rec_data.ended = false;
+        self.metadata_changed(rec_data);
+        start
+    }
+
+    /// Returns the timestamp for a new entry, or `None` outside of a session or while the
+    /// recording is stopped. With `StartMode::FirstEntry` the first entry starts the session.
+    fn entry_time(&self, rec_data: &mut RecData) -> Option<u128> {
+        let start = match rec_data.start {
+            _ if rec_data.ended || !self.is_recording() => return None,
+            Some(start) => start,
+            None if rec_data.start_mode == StartMode::FirstEntry => self.start(rec_data),
+            None => return None,
+        };
+        Some(rec_data.time_unit.since(start))
+    }
+
+    pub fn set_metadata(&self, key: &str, value: impl ToString) {
+        if let Err(e) = self.try_set_metadata(key, value) {
+            println!("Failed to set metadata: {}", e);
+        }
+    }
+
+    /// Sets a key/value pair describing the recording, like the PID gains or the battery level.
+    /// It is written to the header of the files and sent to the clients.
+    /// Fails if the key is empty, reserved ("time_unit", "start_time", "recording_id" and
+    /// "custom_channels") or contains ':',
+    /// or if the key or value contain a line break.
+    pub fn try_set_metadata(&self, key: &str, value: impl ToString) -> Result<()> {
+        let value = value.to_string();
+        if key.is_empty()
+            || key == TIME_UNIT_KEY
+            || key == START_TIME_KEY
+            || key == RECORDING_ID_KEY
+            || key == CUSTOM_CHANNELS_KEY
+            || key.contains([':', '\n', '\r'])
+            || value.contains(['\n', '\r'])
+        {
+            return Err(Error::Parse(format!("Invalid metadata: {}: {}", key, value)));
+        }
+        let mut rec_data = self.lock();
+        rec_data.set_header_metadata(vec![(key.to_string(), value)])?;
+        self.metadata_changed(&rec_data);
+        Ok(())
+    }
+
+    pub fn get_metadata(&self, key: &str) -> Option<String> {
+        self.lock()
+            .metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+    }
+
+    pub fn get_data_name(&self) -> String {
+        let rec_data = self.lock();
+        println!("COMMANDS: {:?}", rec_data.commands);
+        "data_".to_string()
+            + &*rec_data
+                .commands
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>()
+                .join("_")
+                .replace(' ', "")
+                .to_string()
+    }
+
+    /// Returns the time since the start of the session in its time unit,
+    /// `None` outside of a session.
+    pub fn get_time(&self) -> Option<u128> {
+        let rec_data = self.lock();
+        match rec_data.start {
+            Some(start) if !rec_data.ended => Some(rec_data.time_unit.since(start)),
+            _ => None,
+        }
+    }
+
+    /// Same as `try_set_time_unit`, but only logs the error.
+    pub fn set_time_unit(&self, unit: TimeUnit) {
+        if let Err(e) = self.try_set_time_unit(unit) {
+            println!("{}", e);
+        }
+    }
+
+    /// Sets the unit of the timestamps, microseconds by default.
+    /// Fails once the session has started, until `clear_data` is called.
+    pub fn try_set_time_unit(&self, unit: TimeUnit) -> Result<()> {
+        let mut rec_data = self.lock();
+        if rec_data.start.is_some() {
+            return Err(Error::Session(
+                "Can't change the time unit during a recording".to_string(),
+            ));
+        }
+        rec_data.time_unit = unit;
+        self.metadata_changed(&rec_data);
+        Ok(())
+    }
+
+    /// Returns the metadata as it is written to files, including the time unit and start time.
+    pub fn header_metadata(&self) -> Vec<(String, String)> {
+        self.lock().header_metadata()
+    }
+
+    pub fn save_data(&self, data: Vec<DataType>) {
+        self.try_save_data(data).unwrap()
+    }
+
+    /// Same as `save_data`, but returns an error if the data contains the same DataType multiple
+    /// times or data of a custom channel that is not registered.
+    pub fn try_save_data(&self, mut data: Vec<DataType>) -> Result<()> {
+        if !self.is_recording() {
+            return Ok(());
+        }
+        // check if the data contains the same DataType multiple times
+        let mut had = vec![];
+        for d in &data {
+            if had.contains(&d.column()) {
+                return Err(Error::DuplicateDataType(*d));
+            }
+            had.push(d.column());
+            // it could neither be written nor sent
+            if let DataType::Custom(c) = d {
+                if custom_channel(c.channel()).is_none() {
+                    return Err(Error::CustomChannel(format!(
+                        "Unknown custom channel: {}",
+                        c.channel()
+                    )));
+                }
+            }
+        }
+        let mut rec_data = self.lock();
+        let Some(time) = self.entry_time(&mut rec_data) else {
+            return Ok(());
+        };
+        // add RIGHT_TOTAL_D and LEFT_TOTAL_D to the DataType::DrivenDistance element
+        for d in data.iter_mut() {
+            if let DataType::DrivenDistance(r, l) = d {
+                *d = DataType::DrivenDistance(
+                    rec_data.right_total_d + *r,
+                    rec_data.left_total_d + *l,
+                );
+            }
+        }
+        let rec = RecordData(time, data);
+        if self.stream.load(SeqCst) && SERVER.load(SeqCst) {
+            add_data(rec.clone());
+        }
+        if let RecordData(t, d) = &rec {
+            self.with_writer(|w| w.write_record(*t, d));
+        }
+        rec_data.data.push(rec);
+        Ok(())
+    }
+
+    pub fn save_record_data(&self, data: Data) {
+        self.lock().data.push(data);
+    }
+
+    pub fn update_total_distance(&self, right: f32, left: f32) {
+        let mut rec_data = self.lock();
+        if !rec_data.first_time {
+            rec_data.right_total_d += right;
+            rec_data.left_total_d += left;
+        } else {
+            rec_data.first_time = false;
+        }
+    }
+
+    pub fn get_right_total_distance(&self) -> f32 {
+        self.lock().right_total_d
+    }
+
+    pub fn get_left_total_distance(&self) -> f32 {
+        self.lock().left_total_d
+    }
+
+    pub fn write_data(&self, file_name: String) {
+        self.try_write_data(file_name).unwrap()
+    }
+
+    /// Same as `write_data`, but returns an error instead of panicking if the file can't be written.
+    pub fn try_write_data(&self, file_name: String) -> Result<()> {
+        let data_name = self.get_data_name();
+        let rec_data = self.get_rec_data();
+        println!(
+            "Writing data to {}: len: {}",
+            file_name,
+            rec_data.data.len()
+        );
+        write_csv(
+            &file_name,
+            &rec_data.data,
+            &data_name,
+            &rec_data.header_metadata(),
+        )
+    }
+
+    pub fn write_binary(&self, file_name: String) {
+        self.try_write_binary(file_name).unwrap()
+    }
+
+    /// Same as `try_write_data`, but writes the compact binary format, see [`crate::binary`].
+    pub fn try_write_binary(&self, file_name: String) -> Result<()> {
+        let data_name = self.get_data_name();
+        let rec_data = self.get_rec_data();
+        println!(
+            "Writing binary data to {}: len: {}",
+            file_name,
+            rec_data.data.len()
+        );
+        let mut writer = BinaryWriter::create(
+            &file_name,
+            &data_name,
+            &rec_data.header_metadata(),
+            DEFAULT_CHUNK_SIZE,
+        )?;
+        for data in rec_data.data {
+            writer.write(data)?;
+        }
+        writer.flush()
+    }
+
+    /// Deletes the recorded data and ends the session, the metadata, the time unit and the start
+    /// mode are kept.
+    pub fn clear_data(&self) {
+        let mut rec_data = self.lock();
+        let metadata = std::mem::take(&mut rec_data.metadata);
+        *rec_data = RecData {
+            metadata,
+            time_unit: rec_data.time_unit,
+            start_mode: rec_data.start_mode,
+            ..RecData::default()
+        };
+        // the clients learn from the missing recording id that their data is gone
+        self.metadata_changed(&rec_data);
+    }
+
+    /// This Function deletes the first n entries from the data, but keeps the rest.
+    pub fn delete_data(&self, n: usize) {
+        let mut rec_data = self.lock();
+        if n < rec_data.data.len() {
+            rec_data.data.drain(0..n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::CustomData;
+    use crate::{ParamType, ParamValue};
+    use std::time::Duration;
+
+    fn times(recorder: &Recorder) -> Vec<u128> {
+        recorder
+            .get_rec_data()
+            .data
+            .iter()
+            .map(|d| match d {
+                Data::RecordData(t, _) => *t,
+                d => panic!("unexpected entry {:?}", d),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn new_session_continues_the_time_of_kept_data() {
+        let recorder = Recorder::new();
+        recorder.save_data(vec![DataType::Distance(1)]);
+        std::thread::sleep(Duration::from_millis(5));
+        recorder.end_session();
+        recorder.save_data(vec![DataType::Distance(2)]);
+        recorder.start_session();
+        recorder.save_data(vec![DataType::Distance(3)]);
+        let times = times(&recorder);
+        assert_eq!(times.len(), 2);
+        assert!(times[1] >= 5_000, "{:?}", times);
+    }
+
+    #[test]
+    fn misuse_of_sessions_is_reported() {
+        let recorder = Recorder::new();
+        recorder.set_start_mode(StartMode::Explicit);
+        recorder.try_set_time_unit(TimeUnit::Millis).unwrap();
+        recorder.try_start_session().unwrap();
+        assert!(matches!(
+            recorder.try_start_session(),
+            Err(Error::Session(_))
+        ));
+        assert!(matches!(
+            recorder.try_set_time_unit(TimeUnit::Micros),
+            Err(Error::Session(_))
+        ));
+        assert_eq!(recorder.get_rec_data().time_unit(), TimeUnit::Millis);
+    }
+
+    #[test]
+    fn new_session_after_clear_starts_at_0() {
+        let recorder = Recorder::new();
+        recorder.save_data(vec![DataType::Distance(1)]);
+        std::thread::sleep(Duration::from_millis(5));
+        recorder.end_session();
+        recorder.clear_data();
+        recorder.start_session();
+        recorder.save_data(vec![DataType::Distance(2)]);
+        let times = times(&recorder);
+        assert!(times[0] < 5_000, "{:?}", times);
+    }
+
+    #[test]
+    fn recording_id_changes_when_the_time_restarts() {
+        let recorder = Recorder::new();
+        assert_eq!(recorder.get_rec_data().recording_id(), 0);
+        recorder.save_data(vec![DataType::Distance(1)]);
+        let id = recorder.get_rec_data().recording_id();
+        assert_ne!(id, 0);
+        recorder.end_session();
+        recorder.start_session();
+        assert_eq!(recorder.get_rec_data().recording_id(), id);
+        recorder.clear_data();
+        assert_eq!(recorder.get_rec_data().recording_id(), 0);
+        recorder.save_data(vec![DataType::Distance(2)]);
+        assert!(recorder.get_rec_data().recording_id() > id);
+        assert!(recorder.try_set_metadata(RECORDING_ID_KEY, 1).is_err());
+    }
+
+    #[test]
+    fn data_of_unknown_custom_channels_is_rejected() {
+        let recorder = Recorder::new();
+        let unknown = DataType::Custom(CustomData {
+            channel: 120,
+            ..CustomData::default()
+        });
+        assert!(matches!(
+            recorder.try_save_data(vec![unknown]),
+            Err(Error::CustomChannel(_))
+        ));
+        assert!(recorder.get_rec_data().data.is_empty());
+    }
+
+    #[test]
+    fn custom_commands_that_could_not_be_read_back_are_rejected() {
+        crate::register_custom_command("Lift", &[("label", ParamType::Text)]).unwrap();
+        let recorder = Recorder::new();
+        for command in [
+            Command::Custom {
+                name: "Turn".to_string(),
+                params: vec![],
+            },
+            Command::Custom {
+                name: "Lift".to_string(),
+                params: vec![("label".to_string(), ParamValue::Text("a, b".to_string()))],
+            },
+            Command::Custom {
+                name: "Lift".to_string(),
+                params: vec![("height".to_string(), ParamValue::Text("top".to_string()))],
+            },
+        ] {
+            assert!(recorder.try_begin_command(command).is_err());
+        }
+        assert!(recorder.get_rec_data().data.is_empty());
+        let lift = Command::custom("Lift", vec![ParamValue::Text("top".to_string())]).unwrap();
+        assert!(recorder.try_begin_command(lift).unwrap().is_some());
+    }
+}
@@ -1,16 +1,49 @@
-use crate::client::PORT;
-use crate::{Data, SERVER};
-use bincode::deserialize;
-use lz4_compression::prelude::compress;
-use std::io::{Read, Write};
+use crate::config::{DropPolicy, ServerConfig};
+use crate::data_types::DATA_TYPE_ID_VERSION;
+use crate::protocol::{
+    read_frame, write_message, Message, MessageType, Reply, Request, PROTOCOL_VERSION,
+};
+use crate::{global_recorder, Data, Error, Result, SERVER};
+use std::collections::VecDeque;
 use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::atomic::AtomicBool;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::{Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-static DATA_QUEUE: Mutex<Vec<Data>> = Mutex::new(Vec::new());
+/// A connected client with its own queue of data that has not been sent to it yet.
+struct Subscriber {
+    id: usize,
+    queue: VecDeque<Data>,
+    capacity: usize,
+    policy: DropPolicy,
+    /// records dropped since the last `Dropped` message to the client
+    dropped: u64,
+    /// answers to the messages of the client, they are sent before the queued data
+    replies: Vec<Message>,
+    /// set by the reader thread when the client closed the connection
+    closed: bool,
+}
+
+static SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(Vec::new());
+/// Notified whenever a subscriber has something to send or the server stops.
+static SUBSCRIBERS_CHANGED: Condvar = Condvar::new();
+static NEXT_SUBSCRIBER: AtomicUsize = AtomicUsize::new(0);
 static STOP_SERVER: AtomicBool = AtomicBool::new(false);
+/// Records dropped for all clients since the start of the program.
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+/// Entries per `Data` frame, small enough that a frame never reaches `MAX_FRAME_LEN`.
+const MAX_ENTRIES_PER_FRAME: usize = 1024;
+
+/// What the sender thread of a client has to do next.
+enum Wake {
+    /// replies, queued data and the amount of dropped records
+    Send(Vec<Message>, Vec<Data>, u64),
+    /// nothing was sent for the heartbeat interval
+    Heartbeat,
+    Stop,
+    Closed,
+}
 
 #[macro_export]
 macro_rules! debug {
@@ -21,115 +54,560 @@ macro_rules! debug {
     };
 }
 
-fn handle_client(mut stream: TcpStream) {
-    let mut data = [0 as u8; 50]; // using 50 byte buffer
+impl Subscriber {
+    fn is_full(&self) -> bool {
+        self.queue.len() >= self.capacity
+    }
+
+    /// Queues the data and applies the drop policy if the queue is full.
+    fn push(&mut self, data: Data) {
+        if self.is_full() {
+            let before = self.queue.len();
+            match self.policy {
+                DropPolicy::DropOldest => {
+                    self.queue.pop_front();
+                }
+                DropPolicy::DropNewest => {
+                    self.dropped += 1;
+                    DROPPED.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    return;
+                }
+                DropPolicy::Downsample => {
+                    // keep every second record, commands and annotations are always kept
+                    let mut keep = false;
+                    self.queue.retain(|d| {
+                        if !matches!(d, Data::RecordData(..) | Data::RecordDataOption(..)) {
+                            return true;
+                        }
+                        keep = !keep;
+                        keep
+                    });
+                    // the queue holds almost only commands and annotations, it must stay bounded
+                    if self.queue.len() == before {
+                        self.queue.pop_front();
+                    }
+                }
+                // add_data waits until there is room, so this only happens after a replay or on stop
+                DropPolicy::Block => {}
+            }
+            let dropped = (before - self.queue.len()) as u64;
+            self.dropped += dropped;
+            DROPPED.fetch_add(dropped, std::sync::atomic::Ordering::SeqCst);
+        }
+        self.queue.push_back(data);
+    }
+}
+
+/// Decodes a frame of a client if its type is one of `allowed`. The payload of any other type is
+/// never decoded, so a client can't make us decompress its `Data`.
+fn decode_from_client(
+    ty: MessageType,
+    payload: Vec<u8>,
+    allowed: &[MessageType],
+) -> Result<Message> {
+    if !allowed.contains(&ty) {
+        return Err(Error::Protocol(format!("Unexpected message: {:?}", ty)));
+    }
+    Message::from_payload(ty, payload)
+}
+
+/// Does the handshake and waits for the client to subscribe.
+/// Returns the id of the new subscriber, or `None` if the client did not subscribe.
+fn open_client(stream: &mut TcpStream, config: &ServerConfig) -> Option<usize> {
+    // a client that connects but never speaks must not block the server
+    let timeout = Some(config.peer_timeout);
+    if let Err(e) = stream
+        .set_read_timeout(timeout)
+        .and_then(|_| stream.set_write_timeout(timeout))
+    {
+        println!("Connection error: {}", e);
+        return None;
+    }
+    if let Err(e) = handshake(stream).map_err(|e| e.or_timeout(timeout)) {
+        println!("Connection error: {}", e);
+        return None;
+    }
+    let message = read_frame(stream)
+        .and_then(|(ty, payload)| decode_from_client(ty, payload, &[MessageType::Request]));
+    match message.map_err(|e| e.or_timeout(timeout)) {
+        Ok(Message::Request(Request::Subscribe {
+            replay_from,
+            recording_id,
+        })) => Some(subscribe(replay_from, recording_id, config)),
+        Ok(m) => {
+            println!("Connection error: expected Subscribe, got {:?}", m);
+            None
+        }
+        Err(e) => {
+            println!("Connection error: {}", e);
+            None
+        }
+    }
+}
+
+fn handle_client(mut stream: TcpStream, id: usize, config: &ServerConfig) {
+    // messages of the client are read on their own thread, so this one only wakes up to send
+    match stream.try_clone() {
+        Ok(reader) => {
+            let reader_config = config.clone();
+            thread::spawn(move || receive_messages(reader, id, &reader_config));
+            if let Err(e) = send_messages(&mut stream, id, config) {
+                println!("Connection error: {}", e.or_timeout(Some(config.peer_timeout)));
+            }
+        }
+        Err(e) => println!("Connection error: {}", e),
+    }
+    // this also ends the reader thread if it is still waiting for a message
+    let _ = stream.shutdown(Shutdown::Both);
+    SUBSCRIBERS.lock().unwrap().retain(|s| s.id != id);
+    SUBSCRIBERS_CHANGED.notify_all();
+}
+
+/// Runs `f` on the subscriber and wakes up the sender threads.
+fn update_subscriber(id: usize, f: impl FnOnce(&mut Subscriber)) {
+    if let Some(s) = SUBSCRIBERS.lock().unwrap().iter_mut().find(|s| s.id == id) {
+        f(s);
+    }
+    SUBSCRIBERS_CHANGED.notify_all();
+}
+
+/// Blocks until the subscriber has something to send, the client closed the connection, the server
+/// stops or nothing was sent for the `heartbeat` interval.
+fn wait_for_subscriber(id: usize, heartbeat: Duration) -> Wake {
+    let deadline = Instant::now() + heartbeat;
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
     loop {
-        stream
-            .set_read_timeout(Option::from(Duration::from_micros(10)))
-            .unwrap();
-        match stream.read(&mut data) {
-            Ok(n) => {
-                if n == 0 {
-                    break;
+        let Some(s) = subscribers.iter_mut().find(|s| s.id == id) else {
+            return Wake::Closed;
+        };
+        if s.closed {
+            return Wake::Closed;
+        }
+        if !s.replies.is_empty() || !s.queue.is_empty() || s.dropped > 0 {
+            let wake = Wake::Send(
+                std::mem::take(&mut s.replies),
+                std::mem::take(&mut s.queue).into(),
+                std::mem::take(&mut s.dropped),
+            );
+            // wake up add_data if it is waiting for room in the queue
+            SUBSCRIBERS_CHANGED.notify_all();
+            return wake;
+        }
+        if STOP_SERVER.load(std::sync::atomic::Ordering::SeqCst) {
+            return Wake::Stop;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Wake::Heartbeat;
+        }
+        subscribers = SUBSCRIBERS_CHANGED
+            .wait_timeout(subscribers, deadline - now)
+            .unwrap()
+            .0;
+    }
+}
+
+/// Sends the replies and the queued data of the subscriber whenever there are some,
+/// and a `Heartbeat` if the connection has been idle for the heartbeat interval.
+fn send_messages(stream: &mut TcpStream, id: usize, config: &ServerConfig) -> Result<()> {
+    loop {
+        match wait_for_subscriber(id, config.heartbeat_interval) {
+            Wake::Send(replies, queue, dropped) => {
+                for reply in replies {
+                    write_message(stream, &reply)?;
                 }
-                debug!("loop");
-                // print received data
-                let text = String::from_utf8_lossy(&data)
-                    .to_string()
-                    .trim_matches(char::from(0))
-                    .to_string();
-                debug!("Received data: {}, len: {}", text, text.len());
-                if text == "hello" {
-                    stream.write(b"hello").unwrap();
+                if dropped > 0 {
+                    write_message(stream, &Message::Dropped(dropped))?;
                 }
+                send_queue(stream, queue)?;
+            }
+            Wake::Heartbeat => write_message(stream, &Message::Heartbeat)?,
+            Wake::Stop => {
+                println!("Terminating connection");
+                return write_message(stream, &Message::Close);
+            }
+            Wake::Closed => {
+                println!("Terminating connection");
+                return Ok(());
+            }
+        }
+    }
+}
 
-                if text.contains("close") {
-                    println!("Terminating connection");
-                    stream.shutdown(Shutdown::Both).unwrap();
-                    SERVER.store(false, std::sync::atomic::Ordering::SeqCst);
-                    break;
+/// Sends the queued entries in order, the annotations as their own messages between the
+/// `Data` of the records and commands around them.
+fn send_queue(stream: &mut TcpStream, queue: Vec<Data>) -> Result<()> {
+    let mut data = vec![];
+    for d in queue {
+        // a replayed history can be long, split it so every frame stays below `MAX_FRAME_LEN`
+        if data.len() >= MAX_ENTRIES_PER_FRAME {
+            debug!("Sending data: {:?}", data);
+            write_message(stream, &Message::Data(std::mem::take(&mut data)))?;
+        }
+        match d {
+            Data::Annotation(time, annotation) => {
+                if !data.is_empty() {
+                    debug!("Sending data: {:?}", data);
+                    write_message(stream, &Message::Data(std::mem::take(&mut data)))?;
                 }
+                write_message(stream, &Message::Annotation(time, annotation))?;
+            }
+            d => data.push(d),
+        }
+    }
+    if !data.is_empty() {
+        debug!("Sending data: {:?}", data);
+        write_message(stream, &Message::Data(data))?;
+    }
+    Ok(())
+}
+
+/// Executes a control request of a client on the global recorder.
+fn execute(request: Request, config: &ServerConfig) -> Result<Reply> {
+    let recorder = global_recorder();
+    match request {
+        Request::Subscribe { .. } => {
+            return Err(Error::Protocol("Already subscribed".to_string()));
+        }
+        Request::StartRecording => recorder.start_recording(),
+        Request::StopRecording => recorder.stop_recording(),
+        Request::ClearData => recorder.clear_data(),
+        Request::DeleteData(n) => recorder.delete_data(n),
+        Request::WriteData(file_name) => {
+            // a client must not be able to write anywhere else on the robot
+            if file_name.is_empty()
+                || file_name == "."
+                || file_name == ".."
+                || file_name.contains(['/', '\\'])
+            {
+                return Err(Error::Parse(format!("Invalid file name: {}", file_name)));
             }
-            Err(_e) => {
-                if STOP_SERVER.load(std::sync::atomic::Ordering::SeqCst) {
-                    println!("Terminating connection");
-                    stream.write(&(0u32).to_le_bytes()).unwrap();
-                    stream.shutdown(Shutdown::Both).unwrap();
-                    SERVER.store(false, std::sync::atomic::Ordering::SeqCst);
+            let path = config.write_dir.join(file_name);
+            recorder.try_write_data(path.to_string_lossy().to_string())?
+        }
+        Request::AddComment(comment) => recorder.add_comment(comment),
+        Request::GetRecLen => return Ok(Reply::RecLen(recorder.get_rec_len())),
+    }
+    Ok(Reply::Done)
+}
+
+/// Reads the messages of the client until it closes the connection.
+/// If the client does not send anything for `peer_timeout`, it is considered dead.
+fn receive_messages(mut stream: TcpStream, id: usize, config: &ServerConfig) {
+    let peer_timeout = config.peer_timeout;
+    loop {
+        let (ty, payload) = match read_frame(&mut stream) {
+            Ok(frame) => frame,
+            Err(e) => match e.or_timeout(Some(peer_timeout)) {
+                Error::Timeout(_) => {
+                    println!("Client {} is dead: no message for {:?}", id, peer_timeout);
                     break;
                 }
-                if DATA_QUEUE.lock().unwrap().is_empty() {
-                    continue;
-                }
-                // send all data from the queue
-                let mut queue = DATA_QUEUE.lock().unwrap().clone();
-                debug!("Queue: {:?}", queue);
-                let data = bincode::serialize(&queue);
-                let data_type: Vec<Data> =
-                    deserialize(&bincode::serialize(&queue).unwrap()).unwrap();
-                debug!("Data type: {:?}", data_type);
-                debug!("Sending data: {:?}", queue);
-                match data {
-                    Ok(d) => {
-                        debug!("length before compression: {}", d.len());
-                        let d = compress(&d);
-                        debug!("length after compression: {}", d.len());
-                        // first send the length of the data
-                        stream.write(&(d.len() as u32).to_le_bytes()).unwrap();
-                        debug!("len: {:?}", (d.len() as u32).to_le_bytes());
-                        stream.write(&d).unwrap();
-                        debug!("Sent data: {:?}", d);
-                        queue.clear();
-                        DATA_QUEUE.lock().unwrap().clear();
-                    }
-                    Err(e) => {
-                        debug!("Failed to serialize data: {}", e);
-                    }
+                e => {
+                    debug!("Stopped reading from client {}: {}", id, e);
+                    break;
                 }
+            },
+        };
+        let allowed = [
+            MessageType::Request,
+            MessageType::Heartbeat,
+            MessageType::Error,
+            MessageType::Close,
+        ];
+        let message = match decode_from_client(ty, payload, &allowed) {
+            Ok(message) => message,
+            Err(e) => {
+                update_subscriber(id, |s| s.replies.push(Message::Error(e.to_string())));
+                continue;
             }
         };
+        debug!("Received message: {:?}", message);
+        match message {
+            Message::Request(request) => {
+                let reply = match execute(request, config) {
+                    Ok(reply) => Message::Reply(reply),
+                    Err(e) => Message::Error(e.to_string()),
+                };
+                update_subscriber(id, |s| s.replies.push(reply));
+            }
+            Message::Heartbeat => {}
+            Message::Error(e) => println!("Client error: {}", e),
+            Message::Close => break,
+            _ => unreachable!("only requests, heartbeats, errors and close are decoded"),
+        }
+    }
+    update_subscriber(id, |s| s.closed = true);
+}
+
+/// Registers a new subscriber and returns its id.
+/// If `replay_from` is given, the queue starts with the recorded history from that timestamp onward.
+/// The timestamps of another recording mean nothing here, so if `recording_id` is not the current
+/// one the whole history is replayed.
+///
+/// The recorder stays locked while the subscriber is added, so no data is lost or sent twice
+/// between the history and the live data.
+fn subscribe(replay_from: Option<u128>, recording_id: Option<u64>, config: &ServerConfig) -> usize {
+    let rec_data = global_recorder().lock();
+    let replay_from = match recording_id {
+        Some(id) if id != rec_data.recording_id() => replay_from.map(|_| 0),
+        _ => replay_from,
+    };
+    let queue = match replay_from {
+        Some(from) => {
+            // start right after the last record before `from`, so the commands in between are replayed too
+            let start = rec_data
+                .data
+                .iter()
+                .rposition(|d| match d {
+                    Data::RecordData(t, _) | Data::RecordDataOption(t, _) => *t < from,
+                    Data::Command(..) | Data::CommandEnd(..) | Data::Annotation(..) => false,
+                })
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            debug!("Replaying {} entries from {}", rec_data.data.len() - start, from);
+            rec_data.data[start..].iter().cloned().collect()
+        }
+        None => VecDeque::new(),
+    };
+    let id = NEXT_SUBSCRIBER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    SUBSCRIBERS.lock().unwrap().push(Subscriber {
+        id,
+        queue,
+        capacity: config.queue_capacity.max(1),
+        policy: config.drop_policy,
+        dropped: 0,
+        // the metadata is sent before the data, like a header
+        replies: vec![Message::Metadata(rec_data.header_metadata())],
+        closed: false,
+    });
+    SUBSCRIBERS_CHANGED.notify_all();
+    id
+}
+
+/// Answers the `Hello` of the client, fails if the client speaks another protocol version or
+/// uses other data type ids.
+fn handshake(stream: &mut TcpStream) -> Result<()> {
+    let (ty, payload) = read_frame(stream)?;
+    match decode_from_client(ty, payload, &[MessageType::Hello])? {
+        Message::Hello(PROTOCOL_VERSION, DATA_TYPE_ID_VERSION) => write_message(
+            stream,
+            &Message::Hello(PROTOCOL_VERSION, DATA_TYPE_ID_VERSION),
+        ),
+        Message::Hello(version, id_version) => {
+            let error = if version != PROTOCOL_VERSION {
+                format!(
+                    "Unsupported protocol version {}, the server speaks {}",
+                    version, PROTOCOL_VERSION
+                )
+            } else {
+                format!(
+                    "Unsupported data type id version {}, the server uses {}",
+                    id_version, DATA_TYPE_ID_VERSION
+                )
+            };
+            write_message(stream, &Message::Error(error.clone()))?;
+            write_message(stream, &Message::Close)?;
+            Err(Error::Protocol(error))
+        }
+        m => Err(Error::Protocol(format!("Expected Hello, got {:?}", m))),
     }
 }
 
 pub fn create_server() {
+    create_server_with(ServerConfig::default())
+}
+
+/// Same as `create_server`, but binds to the address and port of the config.
+pub fn create_server_with(config: ServerConfig) {
     if SERVER.load(std::sync::atomic::Ordering::SeqCst) {
         println!("Server already running");
         return;
     }
     SERVER.store(true, std::sync::atomic::Ordering::SeqCst);
     STOP_SERVER.store(false, std::sync::atomic::Ordering::SeqCst);
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", PORT)).unwrap();
-    // accept connections and process them, spawning a new thread for each one
-    println!("Server listening on port 3333");
-    for stream in listener.incoming() {
-        println!("Incoming connection");
-        match stream {
-            Ok(stream) => {
-                println!("New connection: {}", stream.peer_addr().unwrap());
-                thread::spawn(move || {
-                    // connection succeeded
-                    handle_client(stream)
-                });
-                println!("Connection handled");
-                break;
+    let listener = match TcpListener::bind(config.address()) {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Failed to bind server: {}", e);
+            SERVER.store(false, std::sync::atomic::Ordering::SeqCst);
+            return;
+        }
+    };
+    println!("Server listening on {}", config.address());
+    // wait for the first client to subscribe, so nothing is recorded before someone is listening
+    while !accept_client(&listener, &config, true) {
+        if STOP_SERVER.load(std::sync::atomic::Ordering::SeqCst) {
+            return;
+        }
+    }
+    // accept all other clients in the background
+    if let Err(e) = listener.set_nonblocking(true) {
+        println!("Can't accept any more connections: {}", e);
+        return;
+    }
+    thread::spawn(move || {
+        while !STOP_SERVER.load(std::sync::atomic::Ordering::SeqCst) {
+            if !accept_client(&listener, &config, false) {
+                thread::sleep(config.accept_interval);
             }
-            Err(e) => {
+        }
+        println!("Can't accept any more connections");
+        // close the socket server
+        drop(listener);
+    });
+}
+
+/// Accepts one connection and spawns a new thread for it.
+/// If `wait` is set, the handshake is done on this thread and the connection only counts once
+/// the client subscribed. Returns false if no connection was accepted.
+fn accept_client(listener: &TcpListener, config: &ServerConfig, wait: bool) -> bool {
+    match listener.accept() {
+        Ok((stream, addr)) => {
+            println!("New connection: {}", addr);
+            // the accepted stream must block even if the listener does not
+            if let Err(e) = stream.set_nonblocking(false) {
+                println!("Error: {}", e);
+                return false;
+            }
+            if wait {
+                let mut stream = stream;
+                let Some(id) = open_client(&mut stream, config) else {
+                    return false;
+                };
+                let config = config.clone();
+                thread::spawn(move || handle_client(stream, id, &config));
+                return true;
+            }
+            let config = config.clone();
+            thread::spawn(move || {
+                // connection succeeded
+                let mut stream = stream;
+                if let Some(id) = open_client(&mut stream, &config) {
+                    handle_client(stream, id, &config)
+                }
+            });
+            true
+        }
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::WouldBlock {
                 println!("Error: {}", e);
-                /* connection failed */
             }
+            /* connection failed */
+            false
         }
     }
-    println!("Can't accept any more connections");
-    // close the socket server
-    drop(listener);
 }
 
+/// Adds the data to the queue of every connected client.
+/// If the queue of a client is full, its drop policy decides what happens.
 pub fn add_data(data: Data) {
     debug!("Adding data: {:?}", data);
-    DATA_QUEUE.lock().unwrap().push(data);
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    while !STOP_SERVER.load(std::sync::atomic::Ordering::SeqCst)
+        && subscribers
+            .iter()
+            .any(|s| s.policy == DropPolicy::Block && !s.closed && s.is_full())
+    {
+        subscribers = SUBSCRIBERS_CHANGED.wait(subscribers).unwrap();
+    }
+    for subscriber in subscribers.iter_mut() {
+        subscriber.push(data.clone());
+    }
+    drop(subscribers);
+    SUBSCRIBERS_CHANGED.notify_all();
+}
+
+/// Sends the changed metadata of the recording to every connected client.
+pub fn send_metadata(metadata: Vec<(String, String)>) {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    for subscriber in subscribers.iter_mut() {
+        subscriber
+            .replies
+            .push(Message::Metadata(metadata.clone()));
+    }
+    drop(subscribers);
+    SUBSCRIBERS_CHANGED.notify_all();
+}
+
+/// Returns how many records were dropped for all clients because their queues were full.
+pub fn dropped_records() -> u64 {
+    DROPPED.load(std::sync::atomic::Ordering::SeqCst)
 }
 
 pub fn stop_server() {
     SERVER.store(false, std::sync::atomic::Ordering::SeqCst);
     STOP_SERVER.store(true, std::sync::atomic::Ordering::SeqCst);
+    // take the lock, so no sender thread can miss the stop between its check and its wait
+    let _subscribers = SUBSCRIBERS.lock().unwrap();
+    SUBSCRIBERS_CHANGED.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::DataType;
+    use crate::protocol::read_message;
+    use crate::Annotation;
+
+    fn subscriber(capacity: usize) -> Subscriber {
+        Subscriber {
+            id: 0,
+            queue: VecDeque::new(),
+            capacity,
+            policy: DropPolicy::Downsample,
+            dropped: 0,
+            replies: vec![],
+            closed: false,
+        }
+    }
+
+    fn record(t: u128) -> Data {
+        Data::RecordData(t, vec![DataType::Distance(t as i16)])
+    }
+
+    #[test]
+    fn downsample_drops_every_second_record_and_keeps_annotations() {
+        let mut s = subscriber(4);
+        s.push(record(0));
+        s.push(Data::Annotation(1, Annotation::new("note")));
+        s.push(record(2));
+        s.push(record(3));
+        s.push(record(4));
+        assert_eq!(
+            Vec::from(s.queue),
+            vec![
+                record(0),
+                Data::Annotation(1, Annotation::new("note")),
+                record(3),
+                record(4)
+            ]
+        );
+        assert_eq!(s.dropped, 1);
+    }
+
+    #[test]
+    fn downsample_stays_bounded_without_records() {
+        let mut s = subscriber(3);
+        for t in 0..10 {
+            s.push(Data::Annotation(t, Annotation::new("note")));
+        }
+        assert_eq!(s.queue.len(), 3);
+        assert_eq!(s.dropped, 7);
+    }
+
+    #[test]
+    fn handshake_rejects_other_data_type_ids() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut stream = listener.accept().unwrap().0;
+        let hello = Message::Hello(PROTOCOL_VERSION, DATA_TYPE_ID_VERSION + 1);
+        write_message(&mut client, &hello).unwrap();
+        assert!(handshake(&mut stream).is_err());
+        assert!(matches!(
+            read_message(&mut client).unwrap(),
+            Message::Error(_)
+        ));
+        assert_eq!(read_message(&mut client).unwrap(), Message::Close);
+    }
 }
@@ -0,0 +1,405 @@
+use crate::data_types::DataType;
+use crate::Data::{RecordData, RecordDataOption};
+use crate::{Annotation, Command, CommandEnd, Data, Error, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+use whoami::fallible;
+
+/// Prefix of the metadata lines, which follow the data name in the header.
+pub(crate) const METADATA_PREFIX: &str = "# @";
+
+/// Writes the header of a data file: the column descriptions, "# Phoenix data",
+/// the creation line, the data name and a "# @key: value" line for every metadata entry.
+pub(crate) fn write_header(
+    file: &mut impl Write,
+    descriptions: &[String],
+    data_name: &str,
+    metadata: &[(String, String)],
+) -> Result<()> {
+    file.write_all(
+        format!(
+            "time, {}\n",
+            descriptions
+                .iter()
+                .filter(|x| !x.is_empty())
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
+        .as_bytes(),
+    )?;
+    file.write_all(b"# Phoenix data\n")?;
+    // todo: write time and date in this format: hh:mm:ss dd.mm.yyyy
+    // Get current time, date and the name of the current user and format it in a nice way
+    let now = chrono::Local::now();
+    let user = whoami::username();
+    let machine_name = fallible::hostname().unwrap_or_else(|_| "unknown".to_string());
+    file.write_all(
+        format!(
+            "# Created on {} at {} by {} on {}\n",
+            now.format("%d-%m-%Y"),
+            now.format("%H:%M:%S"),
+            user,
+            machine_name
+        )
+        .as_bytes(),
+    )?;
+    file.write_all(format!("# {}\n", data_name).as_bytes())?;
+    for (key, value) in metadata {
+        file.write_all(format!("{}{}: {}\n", METADATA_PREFIX, key, value).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Prefix of the command lines.
+pub(crate) const COMMAND_PREFIX: &str = "#> ";
+/// Prefix of the lines with the end of a command.
+pub(crate) const COMMAND_END_PREFIX: &str = "#< ";
+/// Prefix of the annotation lines.
+pub(crate) const ANNOTATION_PREFIX: &str = "#! ";
+
+/// Formats the end of a command as "#< [time] [start] outcome Name { param: value }: error"
+/// line, see `CommandEnd::write`.
+pub(crate) fn command_end_line(time: u128, end: &CommandEnd) -> String {
+    format!("{}[{}] {}\n", COMMAND_END_PREFIX, time, end.write())
+}
+
+/// Formats an annotation as "#! [time] (severity, tag) text" line, see `Annotation::write`.
+pub(crate) fn annotation_line(time: u128, annotation: &Annotation) -> String {
+    format!("{}[{}] {}\n", ANNOTATION_PREFIX, time, annotation.write())
+}
+
+/// Formats a command as "#> [time] Name { param: value }" line, see `Command::write`.
+pub(crate) fn command_line(time: u128, command: &Command) -> String {
+    format!("{}[{}] {}\n", COMMAND_PREFIX, time, command.write())
+}
+
+/// Returns the descriptions of the columns, fails if one of them is unknown.
+pub(crate) fn column_descriptions(columns: &[u8]) -> Result<Vec<String>> {
+    columns
+        .iter()
+        .map(|i| {
+            DataType::column_description(*i)
+                .ok_or_else(|| Error::Parse(format!("DataType not found: {}", i)))
+        })
+        .collect()
+}
+
+/// Writes the whole recording as a data file, see `write_data`.
+pub(crate) fn write_csv(
+    file_name: &str,
+    rec_data: &[Data],
+    data_name: &str,
+    metadata: &[(String, String)],
+) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(file_name)?;
+    // println!("Data: {:?}", DATA);
+    let mut data = vec![];
+    let mut used = vec![];
+    for dat in rec_data {
+        match dat {
+            RecordData(t, d) => {
+                // todo: maybe index compression
+                let mut temp = vec![];
+                for i in d {
+                    // convert to u8
+                    let index = i.column();
+                    while temp.len() <= index as usize {
+                        temp.push(None);
+                    }
+                    if !used.contains(&index) {
+                        used.push(index);
+                    }
+                    temp[index as usize] = Some(*i);
+                }
+                data.push(RecordDataOption(*t, temp));
+            }
+            Data::Command(..) | Data::CommandEnd(..) | Data::Annotation(..) => {
+                data.push(dat.clone());
+            }
+            d => {
+                return Err(Error::Parse(format!(
+                    "Data is not RecordData, a command or an annotation: {:?}",
+                    d
+                )));
+            }
+        }
+    }
+    used.sort();
+    let descriptions = column_descriptions(&used)?;
+    write_header(&mut file, &descriptions, data_name, metadata)?;
+    // println!("Data: {:?}", data);
+    for data in data {
+        match data {
+            RecordDataOption(t, d) => {
+                file.write_all(
+                    format!(
+                        "{}, {}\n",
+                        t,
+                        d.iter()
+                            .enumerate()
+                            .map(|(i, x)| {
+                                if let Some(x) = x {
+                                    x.write()
+                                } else if used.contains(&(i as u8)) {
+                                    DataType::None(i as u8).write()
+                                } else {
+                                    // dont print null, because its not needed and we want to save disc space
+                                    "".to_string()
+                                }
+                            })
+                            .filter(|x| x != &"".to_string())
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )
+                    .as_bytes(),
+                )?;
+            }
+            Data::Command(t, c) => {
+                file.write_all(command_line(t, &c).as_bytes())?;
+            }
+            Data::CommandEnd(t, e) => {
+                file.write_all(command_end_line(t, &e).as_bytes())?;
+            }
+            Data::Annotation(t, a) => {
+                file.write_all(annotation_line(t, &a).as_bytes())?;
+            }
+            _ => unreachable!("only RecordDataOption, commands and annotations are collected above"),
+        }
+    }
+    Ok(())
+}
+
+/// Writes a data file row by row while recording, so a crash only loses the rows that were not
+/// flushed yet.
+///
+/// `write_data` only knows which columns were used once the recording is done, this writer has
+/// to write the header first. So the columns are declared up front when it is created and
+/// every record may only contain data types of these columns. The file has the same format as
+/// the one of `write_data` and can be read with `read_data`.
+///
+/// The header is written with the first entry, so it has the metadata of the started session.
+/// Metadata that changes after that is appended as "# @key: value" line.
+#[derive(Debug)]
+pub struct CsvWriter {
+    file: BufWriter<File>,
+    /// the declared columns, sorted like in the header
+    columns: Vec<u8>,
+    descriptions: Vec<String>,
+    data_name: String,
+    /// the metadata of the header, or the metadata written so far once `header_written`
+    metadata: Vec<(String, String)>,
+    header_written: bool,
+    flush_interval: Duration,
+    last_flush: Instant,
+}
+
+impl CsvWriter {
+    /// Creates the file, the header is written with the first entry. `metadata` is the metadata
+    /// of the header until the recorder passes its own with `set_metadata`.
+    ///
+    /// `columns` are the columns of the data types that will be recorded, see `DataType::column`,
+    /// `DataType::columns()` declares every known column. The buffered rows are written to the
+    /// file at the latest after `flush_interval`.
+    pub fn create(
+        file_name: &str,
+        columns: &[u8],
+        data_name: &str,
+        metadata: &[(String, String)],
+        flush_interval: Duration,
+    ) -> Result<Self> {
+        let mut columns = columns.to_vec();
+        columns.sort();
+        columns.dedup();
+        let descriptions = column_descriptions(&columns)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(file_name)?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            columns,
+            descriptions,
+            data_name: data_name.to_string(),
+            metadata: metadata.to_vec(),
+            header_written: false,
+            flush_interval,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Sets the metadata of the recording, usually `header_metadata()` of the recorder.
+    /// Before the first entry it replaces the metadata of the header, after it the changed
+    /// entries are appended.
+    pub fn set_metadata(&mut self, metadata: &[(String, String)]) -> Result<()> {
+        if !self.header_written {
+            self.metadata = metadata.to_vec();
+            return Ok(());
+        }
+        for entry in metadata {
+            if !self.metadata.contains(entry) {
+                self.file.write_all(
+                    format!("{}{}: {}\n", METADATA_PREFIX, entry.0, entry.1).as_bytes(),
+                )?;
+                match self.metadata.iter_mut().find(|(k, _)| *k == entry.0) {
+                    Some((_, v)) => *v = entry.1.clone(),
+                    None => self.metadata.push(entry.clone()),
+                }
+            }
+        }
+        self.flush_if_due()
+    }
+
+    fn write_header_once(&mut self) -> Result<()> {
+        if !self.header_written {
+            write_header(
+                &mut self.file,
+                &self.descriptions,
+                &self.data_name,
+                &self.metadata,
+            )?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// Appends one row, the declared columns that are not in `data` are written as null.
+    pub fn write_record(&mut self, time: u128, data: &[DataType]) -> Result<()> {
+        if let Some(d) = data.iter().find(|d| !self.columns.contains(&d.column())) {
+            return Err(Error::UndeclaredColumn(*d));
+        }
+        self.write_header_once()?;
+        // like write_data, don't write the nulls at the end of the row to save disc space
+        let used = self
+            .columns
+            .iter()
+            .rposition(|c| data.iter().any(|d| d.column() == *c))
+            .map_or(0, |i| i + 1);
+        let row = self.columns[..used]
+            .iter()
+            .map(|column| match data.iter().find(|d| d.column() == *column) {
+                Some(d) => d.write(),
+                None => DataType::None(*column).write(),
+            })
+            .collect::<Vec<String>>();
+        self.file
+            .write_all(format!("{}, {}\n", time, row.join(", ")).as_bytes())?;
+        self.flush_if_due()
+    }
+
+    /// Appends a command line with its named parameters.
+    pub fn write_command(&mut self, time: u128, command: &Command) -> Result<()> {
+        self.write_header_once()?;
+        self.file
+            .write_all(command_line(time, command).as_bytes())?;
+        self.flush_if_due()
+    }
+
+    /// Appends a line with the end of a command.
+    pub fn write_command_end(&mut self, time: u128, end: &CommandEnd) -> Result<()> {
+        self.write_header_once()?;
+        self.file
+            .write_all(command_end_line(time, end).as_bytes())?;
+        self.flush_if_due()
+    }
+
+    /// Appends an annotation line.
+    pub fn write_annotation(&mut self, time: u128, annotation: &Annotation) -> Result<()> {
+        self.write_header_once()?;
+        self.file
+            .write_all(annotation_line(time, annotation).as_bytes())?;
+        self.flush_if_due()
+    }
+
+    /// Writes all buffered rows to the file, and the header if nothing was written yet.
+    pub fn flush(&mut self) -> Result<()> {
+        self.write_header_once()?;
+        self.file.flush()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    fn flush_if_due(&mut self) -> Result<()> {
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CsvWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            println!("Failed to write the last rows: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::read_data;
+    use crate::reader::tests::{sample, temp_file, without_channels};
+    use crate::Recorder;
+
+    #[test]
+    fn streamed_file_matches_sample() {
+        let (data, metadata) = sample();
+        let file = temp_file("streamed.csv");
+        let mut writer = CsvWriter::create(
+            &file,
+            &DataType::columns(),
+            "data_test",
+            &metadata,
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        for d in &data {
+            match d {
+                RecordData(t, d) => writer.write_record(*t, d),
+                Data::Command(t, c) => writer.write_command(*t, c),
+                Data::CommandEnd(t, e) => writer.write_command_end(*t, e),
+                Data::Annotation(t, a) => writer.write_annotation(*t, a),
+                RecordDataOption(..) => unreachable!("the sample has no RecordDataOption"),
+            }
+            .unwrap();
+        }
+        drop(writer);
+        let rec_data = read_data(file.clone()).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(rec_data.data, data);
+        assert_eq!(without_channels(rec_data.header_metadata()), metadata);
+    }
+
+    #[test]
+    fn streamed_file_has_the_metadata_of_the_recorder() {
+        let file = temp_file("streamed_metadata.csv");
+        let columns = [DataType::Distance(0).column()];
+        let writer =
+            CsvWriter::create(&file, &columns, "data_test", &[], Duration::from_secs(1)).unwrap();
+        let recorder = Recorder::new();
+        recorder.set_writer(Some(writer)).unwrap();
+        recorder.set_metadata("battery", 7.9);
+        recorder.save_data(vec![DataType::Distance(3)]);
+        recorder.set_metadata("battery", 7.5);
+        recorder.save_data(vec![]);
+        recorder.set_writer(None).unwrap();
+        let rec_data = read_data(file.clone()).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        let recorded = recorder.get_rec_data();
+        assert_eq!(rec_data.data, recorded.data);
+        assert_eq!(rec_data.start_time(), recorded.start_time());
+        assert_eq!(rec_data.recording_id(), recorded.recording_id());
+        assert_ne!(rec_data.recording_id(), 0);
+        assert_eq!(
+            rec_data.metadata,
+            vec![("battery".to_string(), "7.5".to_string())]
+        );
+    }
+}